@@ -0,0 +1,175 @@
+//! Arrays and dictionaries, built from the values above a mark like
+//! in PostScript: `[ 1 2 3 ]` and `<< /x 1 /y 2 >>`.
+//!
+//! Unlike in PostScript, the brackets are not delimiters but
+//! operators like any other, so they need spaces around them just
+//! as `{` and `}` do: `[1 2 3]` reads the names `[1` and `3]`.
+
+use std::collections::BTreeMap;
+
+use crate::{stack::find_mark, Value, Vm};
+
+/// Pops the values above the topmost mark and the mark itself.
+fn pop_to_mark(vm: &mut Vm) -> Result<Vec<Value>, String> {
+  let depth = find_mark(vm)?;
+  let len = vm.stack.len();
+  let values = vm.stack.split_off(len - depth);
+  vm.stack.pop();
+  Ok(values)
+}
+
+/// `mark a_0 ... a_(n-1) ]` -> `array`
+pub(crate) fn end_array(vm: &mut Vm) -> Result<(), String> {
+  let items = pop_to_mark(vm)?;
+  vm.stack.push(Value::Array(items));
+  Ok(())
+}
+
+/// `mark key_0 value_0 ... >>` -> `dict`
+pub(crate) fn end_dict(vm: &mut Vm) -> Result<(), String> {
+  let values = pop_to_mark(vm)?;
+  if values.len() % 2 != 0 {
+    return Err(
+      "Dictionary needs a value for every key".to_string(),
+    );
+  }
+  let mut entries = BTreeMap::new();
+  let mut values = values.into_iter();
+  while let (Some(key), Some(value)) =
+    (values.next(), values.next())
+  {
+    let key = match key {
+      Value::Sym(s) | Value::Str(s) => s,
+      _ => {
+        return Err(format!(
+          "Expected a name or a string as a key, got {}",
+          key.type_name()
+        ))
+      }
+    };
+    entries.insert(key, value);
+  }
+  vm.stack.push(Value::Dict(entries));
+  Ok(())
+}
+
+/// `array index get` or `dict key get` -> `value`
+pub(crate) fn get(vm: &mut Vm) -> Result<(), String> {
+  let key = vm.try_pop()?;
+  let container = vm.try_pop()?;
+  let value = match (&container, &key) {
+    (Value::Array(items), _) => {
+      let index = key.try_int()?;
+      usize::try_from(index)
+        .ok()
+        .and_then(|index| items.get(index))
+        .ok_or_else(|| {
+          format!(
+            "Index {index} is out of range of an array of \
+            length {}",
+            items.len()
+          )
+        })?
+    }
+    (
+      Value::Dict(entries),
+      Value::Sym(key) | Value::Str(key),
+    ) => entries.get(key).ok_or_else(|| {
+      format!("{key:?} is not in the dictionary")
+    })?,
+    (Value::Dict(_), _) => {
+      return Err(format!(
+        "Expected a name or a string as a key, got {}",
+        key.type_name()
+      ))
+    }
+    _ => {
+      return Err(format!(
+        "Expected an array or a dictionary, got {}",
+        container.type_name()
+      ))
+    }
+  };
+  vm.stack.push(value.clone());
+  Ok(())
+}
+
+/// Pushes the number of items in an array, dictionary or string.
+pub(crate) fn length(vm: &mut Vm) -> Result<(), String> {
+  let value = vm.try_pop()?;
+  let len = match &value {
+    Value::Array(items) => items.len(),
+    Value::Dict(entries) => entries.len(),
+    Value::Str(s) => s.chars().count(),
+    _ => {
+      return Err(format!(
+        "Expected an array, a dictionary or a string, got {}",
+        value.type_name()
+      ))
+    }
+  };
+  vm.stack.push(Value::Int(len as i32));
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test::run;
+
+  #[test]
+  fn test_array() {
+    assert_eq!(
+      run("0 [ 1 2 3 ]"),
+      Ok(vec![
+        Value::Int(0),
+        Value::Array(vec![
+          Value::Int(1),
+          Value::Int(2),
+          Value::Int(3)
+        ])
+      ])
+    );
+    assert_eq!(
+      run("[ 1 2 3 ] dup 1 get exch length"),
+      Ok(vec![Value::Int(2), Value::Int(3)])
+    );
+    assert_eq!(
+      run("[1 2 3]"),
+      Err(
+        "<input>:1:1: \"[1\" is not a defined operation"
+          .to_string()
+      )
+    );
+    assert_eq!(
+      run("[ 1 ] 1 get"),
+      Err(
+        "<input>:1:9: Index 1 is out of range of an array of \
+        length 1"
+          .to_string()
+      )
+    );
+  }
+
+  #[test]
+  fn test_dict() {
+    assert_eq!(
+      run("<< /x 1 /y 2 >> dup /y get exch length"),
+      Ok(vec![Value::Int(2), Value::Int(2)])
+    );
+    assert_eq!(
+      run("<< /x 1 /y >>"),
+      Err(
+        "<input>:1:12: Dictionary needs a value for every key"
+          .to_string()
+      )
+    );
+    assert_eq!(
+      run("<< /x 1 >> /z get"),
+      Err(
+        "<input>:1:15: \"z\" is not in the dictionary"
+          .to_string()
+      )
+    );
+  }
+}
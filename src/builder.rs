@@ -0,0 +1,199 @@
+use std::{
+  io::{BufRead, Write},
+  path::PathBuf,
+};
+
+use crate::{
+  input::StdinInput,
+  sync::{DynFileLoader, Lock},
+  BuiltinGroup, DynBufRead, DynWrite, FileLoader, MaybeSend,
+  MaybeSync, Sandbox, Shared, Vm,
+};
+
+/// Configures a [`Vm`] before creating it.
+///
+/// ```
+/// use rustack::{OutputBuffer, VmBuilder};
+///
+/// let out = OutputBuffer::new();
+/// let mut vm = VmBuilder::new().output(out.clone()).build();
+/// vm.eval_source("a", std::io::Cursor::new("1 2 + puts")).unwrap();
+/// assert_eq!(out.contents(), "3\n");
+/// ```
+pub struct VmBuilder {
+  sandbox: Sandbox,
+  prelude: bool,
+  debug: bool,
+  fs_root: Option<PathBuf>,
+  input: Box<DynBufRead>,
+  output: Box<DynWrite>,
+  error_output: Box<DynWrite>,
+  file_loader: Option<Shared<DynFileLoader>>,
+}
+
+impl Default for VmBuilder {
+  fn default() -> Self {
+    Self {
+      sandbox: Sandbox::default(),
+      prelude: false,
+      debug: cfg!(debug_assertions),
+      fs_root: None,
+      input: Box::<StdinInput>::default(),
+      output: Box::new(std::io::stdout()),
+      error_output: Box::new(std::io::stderr()),
+      file_loader: None,
+    }
+  }
+}
+
+impl VmBuilder {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Registers only the builtins in the given groups. All groups
+  /// are enabled by default.
+  pub fn groups(mut self, groups: &[BuiltinGroup]) -> Self {
+    self.sandbox.groups = groups.to_vec();
+    self
+  }
+
+  pub fn without_group(mut self, group: BuiltinGroup) -> Self {
+    self.sandbox.groups.retain(|g| *g != group);
+    self
+  }
+
+  /// Does not register the builtin with the given name.
+  pub fn remove(mut self, name: &str) -> Self {
+    self.sandbox.removed.insert(name.to_string());
+    self
+  }
+
+  /// Registers the builtin `from` with the name `to` instead.
+  pub fn rename(mut self, from: &str, to: &str) -> Self {
+    self
+      .sandbox
+      .renamed
+      .insert(from.to_string(), to.to_string());
+    self
+  }
+
+  /// Makes `def` and its friends fail to define the given name.
+  pub fn protect(mut self, name: &str) -> Self {
+    self.sandbox.protected.insert(name.to_string());
+    self
+  }
+
+  /// Protects the names of the builtins, including those the host
+  /// adds later to a group, so scripts cannot shadow them.
+  pub fn protect_builtins(mut self, protect: bool) -> Self {
+    self.sandbox.protect_builtins = protect;
+    self
+  }
+
+  /// Loads the standard prelude. See [`Vm::with_prelude`]. It is
+  /// loaded before the sandbox is applied, so its names can be
+  /// protected, but its functions using removed builtins fail when
+  /// called.
+  pub fn prelude(mut self, prelude: bool) -> Self {
+    self.prelude = prelude;
+    self
+  }
+
+  /// See [`Vm::set_debug`].
+  pub fn debug(mut self, debug: bool) -> Self {
+    self.debug = debug;
+    self
+  }
+
+  /// Allows the file operators to access the files under `root`.
+  /// See [`Vm::set_fs_root`].
+  pub fn fs_root(mut self, root: impl Into<PathBuf>) -> Self {
+    self.fs_root = Some(root.into());
+    self
+  }
+
+  /// Sets the source of the input operators. Defaults to stdin.
+  pub fn input(
+    mut self,
+    input: impl BufRead + MaybeSend + 'static,
+  ) -> Self {
+    self.input = Box::new(input);
+    self
+  }
+
+  /// Sets the sink of the printing operators. Defaults to stdout.
+  pub fn output(
+    mut self,
+    output: impl Write + MaybeSend + 'static,
+  ) -> Self {
+    self.output = Box::new(output);
+    self
+  }
+
+  /// Sets the sink of diagnostics. Defaults to stderr.
+  pub fn error_output(
+    mut self,
+    output: impl Write + MaybeSend + 'static,
+  ) -> Self {
+    self.error_output = Box::new(output);
+    self
+  }
+
+  pub fn file_loader(
+    mut self,
+    loader: impl FileLoader + MaybeSend + MaybeSync + 'static,
+  ) -> Self {
+    self.file_loader = Some(Shared::new(loader));
+    self
+  }
+
+  pub fn build(self) -> Vm {
+    let mut vm = Vm::new();
+    // The prelude needs the builtins and defines protected names.
+    if self.prelude {
+      vm.load_prelude();
+    }
+    vm.set_sandbox(self.sandbox);
+    vm.debug = self.debug;
+    vm.fs_root = self.fs_root;
+    vm.input = self.input;
+    vm.output = self.output;
+    vm.error_output = self.error_output;
+    vm.file_loader = self.file_loader;
+    vm
+  }
+}
+
+/// A shared in-memory sink, e.g. to capture the output of a script
+/// in tests. Clones write to the same buffer.
+#[derive(Debug, Clone, Default)]
+pub struct OutputBuffer(Shared<Lock<Vec<u8>>>);
+
+impl OutputBuffer {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Returns the output written so far, replacing invalid UTF-8.
+  pub fn contents(&self) -> String {
+    String::from_utf8_lossy(&self.0.lock()).into_owned()
+  }
+
+  /// Returns the output written so far and clears the buffer.
+  pub fn take(&self) -> String {
+    let buf = std::mem::take(&mut *self.0.lock());
+    String::from_utf8_lossy(&buf).into_owned()
+  }
+}
+
+impl Write for OutputBuffer {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    self.0.lock().extend_from_slice(buf);
+    Ok(buf.len())
+  }
+
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
+}
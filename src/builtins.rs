@@ -0,0 +1,376 @@
+//! Builtin operators organized in groups which a host can select to
+//! sandbox untrusted scripts.
+
+use std::{
+  collections::{HashMap, HashSet},
+  sync::OnceLock,
+  time::Instant,
+};
+
+use crate::{
+  add, array, cos, div, export, file, import, importfrom,
+  include, input, load, lt, mul, op_and, op_def, op_defn,
+  op_for, op_if, op_or, pi, print, run, sin, stack, sub, types,
+  Value, Vm,
+};
+
+#[cfg(feature = "serde")]
+use crate::json;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum BuiltinGroup {
+  /// Arithmetic, comparison and logical operators
+  Math,
+  /// Operand stack manipulation, and building and reading arrays
+  /// and dictionaries
+  Stack,
+  /// Definitions, conditionals, loops, types and exports
+  Control,
+  /// Printing, input, files (which also need
+  /// [`VmBuilder::fs_root`](crate::VmBuilder::fs_root)) and loading
+  /// of scripts and modules with the file loader, and JSON with the
+  /// `serde` feature
+  Io,
+  /// Drawing, which is provided by the host like the wasm page
+  Canvas,
+  /// Reading the clock
+  Time,
+  /// Pseudo random numbers
+  Random,
+}
+
+impl BuiltinGroup {
+  pub const ALL: [Self; 7] = [
+    Self::Math,
+    Self::Stack,
+    Self::Control,
+    Self::Io,
+    Self::Canvas,
+    Self::Time,
+    Self::Random,
+  ];
+
+  pub fn name(&self) -> &'static str {
+    match self {
+      Self::Math => "math",
+      Self::Stack => "stack",
+      Self::Control => "control",
+      Self::Io => "io",
+      Self::Canvas => "canvas",
+      Self::Time => "time",
+      Self::Random => "random",
+    }
+  }
+
+  /// The operators of this group provided by this crate.
+  pub(crate) fn builtins(
+    &self,
+  ) -> &'static [(&'static str, BuiltinFn)] {
+    match self {
+      Self::Math => &[
+        ("+", add),
+        ("-", sub),
+        ("*", mul),
+        ("div", div),
+        ("<", lt),
+        ("or", op_or),
+        ("and", op_and),
+        ("sin", sin),
+        ("cos", cos),
+        ("pi", pi),
+      ],
+      Self::Stack => &[
+        ("pop", stack::pop),
+        ("dup", stack::dup),
+        ("exch", stack::exch),
+        ("index", stack::index),
+        ("roll", stack::roll),
+        ("copy", stack::copy),
+        ("clear", stack::clear),
+        ("count", stack::count),
+        ("mark", stack::mark),
+        ("cleartomark", stack::cleartomark),
+        ("counttomark", stack::counttomark),
+        ("over", stack::over),
+        ("rot", stack::rot),
+        ("nip", stack::nip),
+        ("tuck", stack::tuck),
+        ("2dup", stack::dup2),
+        ("[", stack::mark),
+        ("]", array::end_array),
+        ("<<", stack::mark),
+        (">>", array::end_dict),
+        ("get", array::get),
+        ("length", array::length),
+      ],
+      Self::Control => &[
+        ("if", op_if),
+        ("for", op_for),
+        ("def", op_def),
+        ("defn", op_defn),
+        ("load", load),
+        ("export", export),
+        ("type", types::type_of),
+        ("cvi", types::cvi),
+        ("cvr", types::cvr),
+        ("cvs", types::cvs),
+        ("cvn", types::cvn),
+        ("cvx", types::cvx),
+        ("cvlit", types::cvlit),
+        ("isint", types::isint),
+        ("isreal", types::isreal),
+        ("isnum", types::isnum),
+        ("isstr", types::isstr),
+        ("issym", types::issym),
+        ("isblock", types::isblock),
+        ("isnative", types::isnative),
+        ("isarray", types::isarray),
+        ("isdict", types::isdict),
+      ],
+      Self::Io => &[
+        ("puts", print::puts),
+        ("print", print::print),
+        ("=", print::print_text),
+        ("==", print::print_repr),
+        ("pstack", print::pstack),
+        ("stack", print::stack),
+        ("format", print::format),
+        ("readline", input::readline),
+        ("read", input::read),
+        ("readall", input::readall),
+        ("file", file::file),
+        ("readstring", file::readstring),
+        ("writestring", file::writestring),
+        ("closefile", file::closefile),
+        ("deletefile", file::deletefile),
+        ("status", file::status),
+        ("include", include),
+        ("run", run),
+        ("import", import),
+        ("importfrom", importfrom),
+        #[cfg(feature = "serde")]
+        ("json-parse", json::json_parse),
+        #[cfg(feature = "serde")]
+        ("json-stringify", json::json_stringify),
+      ],
+      Self::Canvas => &[],
+      Self::Time => &[("usertime", usertime)],
+      Self::Random => {
+        &[("rand", rand), ("srand", srand), ("rrand", rrand)]
+      }
+    }
+  }
+}
+
+impl std::fmt::Display for BuiltinGroup {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    write!(f, "{}", self.name())
+  }
+}
+
+pub(crate) type BuiltinFn = fn(&mut Vm) -> Result<(), String>;
+
+/// Which builtins are available to scripts, set by
+/// [`VmBuilder`](crate::VmBuilder).
+#[derive(Debug, Clone)]
+pub struct Sandbox {
+  pub groups: Vec<BuiltinGroup>,
+  /// Builtins which are not registered even if their group is
+  pub removed: HashSet<String>,
+  /// Builtins registered under another name, by original name
+  pub renamed: HashMap<String, String>,
+  /// Names which scripts cannot define
+  pub protected: HashSet<String>,
+  /// Whether scripts cannot define the names of the builtins of
+  /// any group either
+  pub protect_builtins: bool,
+}
+
+impl Default for Sandbox {
+  fn default() -> Self {
+    Self {
+      groups: BuiltinGroup::ALL.to_vec(),
+      removed: HashSet::new(),
+      renamed: HashMap::new(),
+      protected: HashSet::new(),
+      protect_builtins: false,
+    }
+  }
+}
+
+impl Sandbox {
+  /// Returns the name to register a builtin with, or `None` if it
+  /// should not be registered.
+  pub(crate) fn name_of<'a>(
+    &'a self,
+    group: BuiltinGroup,
+    name: &'a str,
+  ) -> Option<&'a str> {
+    if !self.groups.contains(&group)
+      || self.removed.contains(name)
+    {
+      return None;
+    }
+    Some(self.renamed.get(name).map_or(name, |s| s.as_str()))
+  }
+}
+
+/// An operator available in a `Vm`, returned by
+/// [`Vm::operators`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct OperatorInfo {
+  pub name: String,
+  /// The group of a builtin, or `None` for a function added by
+  /// [`Vm::add_fn`].
+  pub group: Option<BuiltinGroup>,
+  pub protected: bool,
+}
+
+/// Pushes the milliseconds since an arbitrary point in time, which
+/// stop at `i32::MAX` after about 24 days.
+fn usertime(vm: &mut Vm) -> Result<(), String> {
+  static ORIGIN: OnceLock<Instant> = OnceLock::new();
+  let elapsed = ORIGIN.get_or_init(Instant::now).elapsed();
+  let millis = i32::try_from(elapsed.as_millis());
+  vm.stack.push(Value::Int(millis.unwrap_or(i32::MAX)));
+  Ok(())
+}
+
+/// Advances the xorshift state and returns a value in
+/// `0..=i32::MAX`.
+fn next_random(state: &mut u32) -> i32 {
+  let mut x = *state;
+  x ^= x << 13;
+  x ^= x >> 17;
+  x ^= x << 5;
+  *state = x;
+  (x >> 1) as i32
+}
+
+fn rand(vm: &mut Vm) -> Result<(), String> {
+  let value = next_random(&mut vm.random_state);
+  vm.stack.push(Value::Int(value));
+  Ok(())
+}
+
+fn srand(vm: &mut Vm) -> Result<(), String> {
+  let seed = vm.try_pop()?.try_int()?;
+  // A state of zero would produce only zeros.
+  vm.random_state = (seed as u32).max(1);
+  Ok(())
+}
+
+/// Pushes the current state of the generator, which can be given
+/// to `srand` to repeat the sequence.
+fn rrand(vm: &mut Vm) -> Result<(), String> {
+  vm.stack.push(Value::Int(vm.random_state as i32));
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test::run_in;
+  use crate::VmBuilder;
+
+  #[test]
+  fn test_sandbox() {
+    let mut vm = VmBuilder::new()
+      .groups(&[BuiltinGroup::Math, BuiltinGroup::Control])
+      .remove("div")
+      .rename("+", "add")
+      .protect_builtins(true)
+      .build();
+    assert_eq!(
+      run_in(&mut vm, "1 2 add"),
+      Ok(vec![Value::Int(3)])
+    );
+    assert_eq!(
+      run_in(&mut vm, "1 2 div"),
+      Err(
+        "a:1:5: \"div\" is not a defined operation".to_string()
+      )
+    );
+    assert_eq!(
+      run_in(&mut vm, "1 dup"),
+      Err(
+        "a:1:3: \"dup\" is not a defined operation".to_string()
+      )
+    );
+    assert_eq!(
+      run_in(&mut vm, "/sin { 1 } def"),
+      Err("a:1:12: \"sin\" is protected".to_string())
+    );
+    let ops = vm.operators();
+    let add = ops.iter().find(|op| op.name == "add").unwrap();
+    assert_eq!(add.group, Some(BuiltinGroup::Math));
+    assert!(add.protected);
+    assert!(ops.iter().all(|op| op.name != "puts"));
+    assert!(ops.iter().all(|op| op.name != "include"));
+  }
+
+  #[test]
+  fn test_sandbox_prelude() {
+    let mut vm = VmBuilder::new()
+      .groups(&[BuiltinGroup::Math, BuiltinGroup::Control])
+      .protect("square")
+      .rename("def", "define")
+      .prelude(true)
+      .build();
+    assert_eq!(
+      run_in(&mut vm, "3 double"),
+      Ok(vec![Value::Int(6)])
+    );
+    assert_eq!(
+      run_in(&mut vm, "3 square"),
+      Err(
+        "<prelude>/math.txt:2:11: \"dup\" is not a defined \
+        operation"
+          .to_string()
+      )
+    );
+    assert_eq!(
+      run_in(&mut vm, "/square 1 define"),
+      Err("a:1:11: \"square\" is protected".to_string())
+    );
+    assert_eq!(
+      run_in(&mut vm, "/x 1 def"),
+      Err(
+        "a:1:6: \"def\" is not a defined operation".to_string()
+      )
+    );
+  }
+
+  #[test]
+  fn test_protect_later_builtins() {
+    let mut vm =
+      VmBuilder::new().protect_builtins(true).build();
+    vm.add_builtin_fn(
+      BuiltinGroup::Canvas,
+      "moveto",
+      Box::new(|_| ()),
+    );
+    assert!(vm.is_protected("moveto"));
+    assert_eq!(
+      run_in(&mut vm, "/moveto { } def"),
+      Err("a:1:13: \"moveto\" is protected".to_string())
+    );
+    vm.set_global("moveto", Value::Int(1));
+    assert_eq!(
+      run_in(&mut vm, "/moveto 2 def moveto"),
+      Ok(vec![Value::Int(2)])
+    );
+  }
+
+  #[test]
+  fn test_random() {
+    let mut vm = Vm::new();
+    let a = run_in(&mut vm, "42 srand rand rand").unwrap();
+    let b = run_in(&mut vm, "42 srand rand rand").unwrap();
+    assert_eq!(a, b);
+    assert_ne!(a[0], a[1]);
+  }
+}
@@ -0,0 +1,365 @@
+//! Conversions between Rust values and [`Value`]s, which let
+//! [`Vm::register`] accept ordinary Rust closures as operators.
+
+use std::any::Any;
+
+use crate::{
+  sync::NativeFn, HostValue, MaybeSend, MaybeSync, Shared,
+  Value, Vm,
+};
+
+/// A type which can be taken from the stack as an argument of a
+/// registered function.
+pub trait FromValue: Sized {
+  fn from_value(value: &Value) -> Result<Self, String>;
+}
+
+/// A type which can be pushed to the stack.
+pub trait IntoValue {
+  fn into_value(self) -> Value;
+}
+
+/// The return type of a registered function, which pushes zero or
+/// more values.
+///
+/// `Option` follows `readline`: `Some(x)` pushes `x` and `1`, and
+/// `None` pushes only `0`. `Err` of a `Result` raises an error.
+pub trait IntoResults {
+  fn push_to(self, vm: &mut Vm) -> Result<(), String>;
+}
+
+impl FromValue for i32 {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    value.try_int()
+  }
+}
+
+impl FromValue for f32 {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    value.try_num()
+  }
+}
+
+impl FromValue for f64 {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    value.try_num().map(f64::from)
+  }
+}
+
+impl FromValue for bool {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    value.try_bool()
+  }
+}
+
+impl FromValue for String {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    match value {
+      Value::Str(s) => Ok(s.clone()),
+      _ => Err(format!(
+        "Expected a string, got {}",
+        value.type_name()
+      )),
+    }
+  }
+}
+
+impl FromValue for HostValue {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    match value {
+      Value::Host(host) => Ok(host.clone()),
+      _ => Err(format!(
+        "Expected a host object, got {}",
+        value.type_name()
+      )),
+    }
+  }
+}
+
+/// Downcasts a host object to `T`.
+impl<T: Any + MaybeSend + MaybeSync> FromValue for Shared<T> {
+  fn from_value(value: &Value) -> Result<Self, String> {
+    HostValue::from_value(value)?.downcast().ok_or_else(|| {
+      format!(
+        "Expected {}, got {}",
+        std::any::type_name::<T>(),
+        value.type_name()
+      )
+    })
+  }
+}
+
+impl IntoValue for i32 {
+  fn into_value(self) -> Value {
+    Value::Int(self)
+  }
+}
+
+impl IntoValue for f32 {
+  fn into_value(self) -> Value {
+    Value::Num(self)
+  }
+}
+
+impl IntoValue for f64 {
+  fn into_value(self) -> Value {
+    Value::Num(self as f32)
+  }
+}
+
+impl IntoValue for bool {
+  fn into_value(self) -> Value {
+    Value::Int(self as i32)
+  }
+}
+
+impl IntoValue for String {
+  fn into_value(self) -> Value {
+    Value::Str(self)
+  }
+}
+
+impl IntoValue for HostValue {
+  fn into_value(self) -> Value {
+    Value::Host(self)
+  }
+}
+
+impl IntoValue for &str {
+  fn into_value(self) -> Value {
+    Value::Str(self.to_string())
+  }
+}
+
+macro_rules! impl_into_results {
+  {$($ty:ty),*} => {
+    $(impl IntoResults for $ty {
+      fn push_to(self, vm: &mut Vm) -> Result<(), String> {
+        vm.stack.push(self.into_value());
+        Ok(())
+      }
+    })*
+  }
+}
+
+impl_into_results!(
+  i32, f32, f64, bool, String, &str, HostValue
+);
+
+impl IntoResults for () {
+  fn push_to(self, _vm: &mut Vm) -> Result<(), String> {
+    Ok(())
+  }
+}
+
+impl<T: IntoResults> IntoResults for Option<T> {
+  fn push_to(self, vm: &mut Vm) -> Result<(), String> {
+    match self {
+      Some(value) => {
+        value.push_to(vm)?;
+        vm.stack.push(Value::Int(1));
+      }
+      None => vm.stack.push(Value::Int(0)),
+    }
+    Ok(())
+  }
+}
+
+impl<T: IntoResults, E: std::fmt::Display> IntoResults
+  for Result<T, E>
+{
+  fn push_to(self, vm: &mut Vm) -> Result<(), String> {
+    self.map_err(|e| e.to_string())?.push_to(vm)
+  }
+}
+
+macro_rules! impl_tuple_results {
+  {$($name:ident),*} => {
+    impl<$($name: IntoValue),*> IntoResults for ($($name,)*) {
+      #[allow(non_snake_case)]
+      fn push_to(self, vm: &mut Vm) -> Result<(), String> {
+        let ($($name,)*) = self;
+        $(vm.stack.push($name.into_value());)*
+        Ok(())
+      }
+    }
+  }
+}
+
+impl_tuple_results!(A, B);
+impl_tuple_results!(A, B, C);
+impl_tuple_results!(A, B, C, D);
+
+/// A Rust function which can be registered as an operator. It is
+/// implemented for closures taking up to 8 arguments of
+/// [`FromValue`] types and returning an [`IntoResults`] type.
+pub trait NativeFunction<Args> {
+  /// Converts into a native operator with the given name, which is
+  /// used in errors.
+  fn into_native(self, name: &str) -> Shared<Box<NativeFn>>;
+}
+
+/// Returns where the `N` arguments start on the stack, checking
+/// the number of them first. They are popped only once all of them
+/// are converted, so that the stack is intact after an error.
+fn args_start<const N: usize>(
+  vm: &Vm,
+  name: &str,
+) -> Result<usize, String> {
+  if vm.stack.len() < N {
+    return Err(format!(
+      "{name:?} expects {N} argument(s), but the stack has {}",
+      vm.stack.len()
+    ));
+  }
+  Ok(vm.stack.len() - N)
+}
+
+macro_rules! impl_native_function {
+  {$n:literal; $($arg:ident),*} => {
+    impl<Fun, Ret, $($arg),*> NativeFunction<($($arg,)*)> for Fun
+    where
+      Fun: Fn($($arg),*) -> Ret + MaybeSend + MaybeSync + 'static,
+      Ret: IntoResults,
+      $($arg: FromValue,)*
+    {
+      #[allow(non_snake_case, unused_mut, unused_variables)]
+      fn into_native(self, name: &str) -> Shared<Box<NativeFn>> {
+        let name = name.to_string();
+        Shared::new(Box::new(move |vm: &mut Vm| {
+          let start = args_start::<$n>(vm, &name)?;
+          let mut args = vm.stack[start..].iter().enumerate();
+          $(let $arg = {
+            let (i, value) = args.next().unwrap();
+            $arg::from_value(value).map_err(|e| {
+              format!("Argument {} of {name:?}: {e}", i + 1)
+            })?
+          };)*
+          vm.stack.truncate(start);
+          self($($arg),*).push_to(vm)
+        }))
+      }
+    }
+  }
+}
+
+impl_native_function!(0;);
+impl_native_function!(1; A1);
+impl_native_function!(2; A1, A2);
+impl_native_function!(3; A1, A2, A3);
+impl_native_function!(4; A1, A2, A3, A4);
+impl_native_function!(5; A1, A2, A3, A4, A5);
+impl_native_function!(6; A1, A2, A3, A4, A5, A6);
+impl_native_function!(7; A1, A2, A3, A4, A5, A6, A7);
+impl_native_function!(8; A1, A2, A3, A4, A5, A6, A7, A8);
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test::run_in;
+
+  #[test]
+  fn test_register() {
+    use Value::*;
+    let mut vm = Vm::new();
+    vm.register("sub3", |a: i32, b: i32, c: i32| a - b - c);
+    vm.register(
+      "sum8",
+      |a: i32,
+       b: i32,
+       c: i32,
+       d: i32,
+       e: i32,
+       f: i32,
+       g: i32,
+       h: i32| { a + b + c + d + e + f + g + h },
+    );
+    vm.register("greet", |name: String| {
+      format!("hello {name}")
+    });
+    vm.register("divmod", |a: i32, b: i32| (a / b, a % b));
+    vm.register("sqrt", |x: f64| (x >= 0.).then(|| x.sqrt()));
+    vm.register("checked", |x: i32| {
+      if x < 0 {
+        Err("negative")
+      } else {
+        Ok(x)
+      }
+    });
+    assert_eq!(
+      run_in(&mut vm, "10 3 2 sub3"),
+      Ok(vec![Int(5)])
+    );
+    assert_eq!(
+      run_in(&mut vm, "1 2 3 4 5 6 7 8 sum8"),
+      Ok(vec![Int(36)])
+    );
+    assert_eq!(
+      run_in(&mut vm, "(you) greet"),
+      Ok(vec![Str("hello you".to_string())])
+    );
+    assert_eq!(
+      run_in(&mut vm, "7 2 divmod"),
+      Ok(vec![Int(3), Int(1)])
+    );
+    assert_eq!(
+      run_in(&mut vm, "4 sqrt -1 sqrt"),
+      Ok(vec![Num(2.), Int(1), Int(0)])
+    );
+    assert_eq!(
+      run_in(&mut vm, "-1 checked"),
+      Err("a:1:4: negative".to_string())
+    );
+    assert_eq!(
+      run_in(&mut vm, "1 2 sub3"),
+      Err(
+        "a:1:5: \"sub3\" expects 3 argument(s), but the stack has 2"
+          .to_string()
+      )
+    );
+    vm.stack.clear();
+    assert_eq!(
+      run_in(&mut vm, "1 /a 3 sub3"),
+      Err(
+        "a:1:8: Argument 2 of \"sub3\": Expected an integer, \
+          got nametype"
+          .to_string()
+      )
+    );
+    assert_eq!(
+      vm.stack,
+      vec![Int(1), Sym("a".to_string()), Int(3)]
+    );
+  }
+
+  #[test]
+  fn test_host() {
+    use std::sync::atomic::{AtomicI32, Ordering};
+    use Value::*;
+    struct Counter(AtomicI32);
+    let mut vm = Vm::new();
+    vm.register("counter", || {
+      HostValue::new("counter", Counter(AtomicI32::new(0)))
+    });
+    vm.register("incr", |c: Shared<Counter>| {
+      c.0.fetch_add(1, Ordering::Relaxed) + 1
+    });
+    vm.register("id", || HostValue::with_display("id", 42));
+    assert_eq!(
+      run_in(&mut vm, "/c counter def c incr c incr c type"),
+      Ok(vec![Int(1), Int(2), Sym("counter".to_string())])
+    );
+    assert_eq!(
+      run_in(&mut vm, "id cvs"),
+      Ok(vec![Str("42".to_string())])
+    );
+    assert_eq!(
+      run_in(&mut vm, "id incr"),
+      Err(
+        "a:1:4: Argument 1 of \"incr\": Expected \
+        rustack::convert::test::test_host::Counter, got id"
+          .to_string()
+      )
+    );
+  }
+}
@@ -0,0 +1,660 @@
+//! Breakpoints, watchpoints and stepping over the execution of a
+//! `Vm`, built on [`Vm::eval_step`].
+//!
+//! The execution pauses before the next token to run, so a paused
+//! `Vm` can be inspected with [`Vm::get_exec_stack`] and resumed by
+//! any of the stepping methods.
+
+use crate::{
+  BlockSpan, EvalError, ExecFrame, ExecState, ParseError,
+  Value, Vm,
+};
+
+/// Where a breakpoint pauses the execution.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum BreakLocation {
+  /// Before the token covering a byte offset in the span space of
+  /// the [`SourceMap`](crate::SourceMap). A block matches only at
+  /// its opening brace.
+  Offset(usize),
+  /// Before the first token of a line in a file, with a 1-based
+  /// line number. Tokens following another one on the same line do
+  /// not pause again.
+  Line { file: String, line: usize },
+  /// On entering a function of the name.
+  Function(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct Breakpoint {
+  pub id: usize,
+  pub location: BreakLocation,
+  /// The source of the condition, which pauses the execution only
+  /// if it leaves a true value on the stack.
+  pub condition: Option<String>,
+  compiled: Option<BlockSpan>,
+}
+
+/// Pauses the execution when a variable of the name is defined in
+/// any frame or at the top level, or set as a global by a native
+/// function, while stepping with the debugger or
+/// [`Vm::eval_step`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+  pub id: usize,
+  pub name: String,
+}
+
+/// A definition of a watched variable.
+#[derive(Debug, Clone, PartialEq)]
+pub struct WatchHit {
+  /// The id of the watchpoint
+  pub id: usize,
+  pub name: String,
+  /// The value replaced by the definition, or `None` if the
+  /// variable was not defined in the same scope.
+  pub old: Option<Value>,
+  pub new: Value,
+  /// The span of the token which defined it, like `def`, or `None`
+  /// if a native function set it with [`Vm::set_global`]
+  pub span: Option<(usize, usize)>,
+}
+
+/// The breakpoints and watchpoints of a `Vm`.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Debugger {
+  breakpoints: Vec<Breakpoint>,
+  watchpoints: Vec<Watchpoint>,
+  /// Ids are shared by breakpoints and watchpoints.
+  next_id: usize,
+  /// The definitions of watched variables not reported yet
+  pub(crate) watch_hits: Vec<WatchHit>,
+  /// Whether definitions are recorded, which is only while a step
+  /// of the debugger or [`Vm::eval_step`] is running, so that
+  /// running to completion does not collect them
+  pub(crate) watching: bool,
+  /// Whether a stepping method paused the execution, so that the
+  /// next one does not hit a breakpoint at the same token again.
+  /// [`Vm::eval_step`] clears it.
+  pub(crate) paused: bool,
+}
+
+impl Debugger {
+  pub(crate) fn is_watched(&self, name: &str) -> bool {
+    self
+      .watchpoints
+      .iter()
+      .any(|watchpoint| watchpoint.name == name)
+  }
+
+  /// Records a definition of a variable for every watchpoint of
+  /// its name.
+  pub(crate) fn hit_watchpoints(
+    &mut self,
+    name: &str,
+    old: Option<Value>,
+    new: Value,
+    span: Option<(usize, usize)>,
+  ) {
+    if !self.watching {
+      return;
+    }
+    let ids = self
+      .watchpoints
+      .iter()
+      .filter(|watchpoint| watchpoint.name == name)
+      .map(|watchpoint| watchpoint.id);
+    self.watch_hits.extend(ids.map(|id| WatchHit {
+      id,
+      name: name.to_string(),
+      old: old.clone(),
+      new: new.clone(),
+      span,
+    }));
+  }
+}
+
+/// Why a stepping method returned.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Pause {
+  /// The step has completed.
+  Step,
+  /// A breakpoint of the id has been hit.
+  Breakpoint(usize),
+  /// Watched variables have been defined, in the order of the
+  /// definitions.
+  Watchpoint(Vec<WatchHit>),
+  /// Nothing is left to run.
+  Finished,
+}
+
+impl Vm {
+  /// Adds a breakpoint and returns its id. The condition is a
+  /// rustack expression evaluated in the paused frame, like
+  /// `i 10 <`, whose definitions and stack operations are discarded.
+  pub fn add_breakpoint(
+    &mut self,
+    location: BreakLocation,
+    condition: Option<&str>,
+  ) -> Result<usize, ParseError> {
+    let id = self.debugger.next_id;
+    let compiled = condition
+      .map(|condition| {
+        let name = format!("<breakpoint {id}>");
+        let start = self
+          .parse_text(&name, condition.to_string())
+          .inspect_err(|_| self.blocks.clear())?;
+        let mut block = self.blocks.pop().unwrap();
+        block.span.1 = start + condition.len();
+        Ok(block)
+      })
+      .transpose()?;
+    self.debugger.next_id += 1;
+    self.debugger.breakpoints.push(Breakpoint {
+      id,
+      location,
+      condition: condition.map(|s| s.to_string()),
+      compiled,
+    });
+    Ok(id)
+  }
+
+  /// Removes a breakpoint and returns whether it existed.
+  pub fn remove_breakpoint(&mut self, id: usize) -> bool {
+    let breakpoints = &mut self.debugger.breakpoints;
+    let len = breakpoints.len();
+    breakpoints.retain(|breakpoint| breakpoint.id != id);
+    breakpoints.len() != len
+  }
+
+  pub fn breakpoints(&self) -> &[Breakpoint] {
+    &self.debugger.breakpoints
+  }
+
+  /// Watches the definitions of a variable and returns the id of
+  /// the watchpoint.
+  pub fn add_watchpoint(&mut self, name: &str) -> usize {
+    let id = self.debugger.next_id;
+    self.debugger.next_id += 1;
+    self.debugger.watchpoints.push(Watchpoint {
+      id,
+      name: name.to_string(),
+    });
+    id
+  }
+
+  /// Removes a watchpoint and returns whether it existed.
+  pub fn remove_watchpoint(&mut self, id: usize) -> bool {
+    let watchpoints = &mut self.debugger.watchpoints;
+    let len = watchpoints.len();
+    watchpoints.retain(|watchpoint| watchpoint.id != id);
+    watchpoints.len() != len
+  }
+
+  pub fn watchpoints(&self) -> &[Watchpoint] {
+    &self.debugger.watchpoints
+  }
+
+  /// Returns the definitions of watched variables in the last
+  /// [`Vm::eval_step`], for hosts stepping with it.
+  pub fn take_watch_hits(&mut self) -> Vec<WatchHit> {
+    std::mem::take(&mut self.debugger.watch_hits)
+  }
+
+  /// Runs a single step, entering functions.
+  pub fn step_into(&mut self) -> Result<Pause, EvalError> {
+    self.run_until(|_| true)
+  }
+
+  /// Runs until the next token of the current frame, running the
+  /// functions and loops it enters to completion.
+  pub fn step_over(&mut self) -> Result<Pause, EvalError> {
+    let depth = self.exec_stack.len();
+    self.run_until(|vm| vm.exec_stack.len() <= depth)
+  }
+
+  /// Runs until the current frame returns.
+  pub fn step_out(&mut self) -> Result<Pause, EvalError> {
+    let depth = self.exec_stack.len();
+    self.run_until(|vm| vm.exec_stack.len() < depth)
+  }
+
+  /// Runs until a breakpoint is hit or nothing is left to run.
+  pub fn resume(&mut self) -> Result<Pause, EvalError> {
+    self.run_until(|_| false)
+  }
+
+  /// Runs at least one step and pauses when `done` returns true,
+  /// at a breakpoint or after a watched variable is defined. A
+  /// breakpoint at the next token is hit before running it, unless
+  /// the execution has paused there already.
+  fn run_until(
+    &mut self,
+    done: impl Fn(&Vm) -> bool,
+  ) -> Result<Pause, EvalError> {
+    self.debugger.watch_hits.clear();
+    self.debugger.watching = true;
+    let pause = if self.debugger.paused {
+      self.run_steps(done)
+    } else {
+      self.hit_breakpoint().and_then(|hit| match hit {
+        Some(id) => Ok(Pause::Breakpoint(id)),
+        None => self.run_steps(done),
+      })
+    };
+    self.debugger.watching = false;
+    self.debugger.paused = matches!(pause, Ok(ref pause) if *pause != Pause::Finished);
+    pause
+  }
+
+  fn run_steps(
+    &mut self,
+    done: impl Fn(&Vm) -> bool,
+  ) -> Result<Pause, EvalError> {
+    loop {
+      if self.debug_step()?.is_none()
+        || self.exec_stack.is_empty()
+      {
+        return Ok(Pause::Finished);
+      }
+      if !self.debugger.watch_hits.is_empty() {
+        return Ok(Pause::Watchpoint(self.take_watch_hits()));
+      }
+      if done(self) {
+        return Ok(Pause::Step);
+      }
+      if let Some(id) = self.hit_breakpoint()? {
+        return Ok(Pause::Breakpoint(id));
+      }
+    }
+  }
+
+  /// Runs a step like [`Vm::eval_step`], but also stops between
+  /// the iterations of a `for`, which `eval_step` runs through, so
+  /// that the debugger can pause before the next one.
+  fn debug_step(
+    &mut self,
+  ) -> Result<Option<(usize, usize)>, EvalError> {
+    if let Some(ExecState::For { frame, i, end }) =
+      self.exec_stack.last_mut()
+    {
+      // The body has run, unless it is empty, when `eval_step`
+      // has not pushed the index either
+      let ran =
+        0 < frame.ip && frame.ip == frame.block.block.len();
+      if ran && *i + 1 < *end {
+        *i += 1;
+        frame.ip = 0;
+        return Ok(Some(frame.block.span));
+      }
+    }
+    self.step()
+  }
+
+  /// Returns the id of a breakpoint at the next token whose
+  /// condition holds.
+  fn hit_breakpoint(
+    &mut self,
+  ) -> Result<Option<usize>, EvalError> {
+    let Some(state) = self.exec_stack.last() else {
+      return Ok(None);
+    };
+    let frame = state.as_frame();
+    let entered =
+      frame.ip == 0 && matches!(state, ExecState::Frame(_));
+    let next = frame.block.block.get(frame.ip);
+    let prev = frame
+      .ip
+      .checked_sub(1)
+      .and_then(|ip| frame.block.block.get(ip));
+    let line_of = |span: (usize, usize)| {
+      self
+        .source_map
+        .lookup(span.0)
+        .map(|loc| (loc.file, loc.line))
+    };
+    let hits: Vec<_> = self
+      .debugger
+      .breakpoints
+      .iter()
+      .filter(|breakpoint| match &breakpoint.location {
+        BreakLocation::Offset(offset) => {
+          next.is_some_and(|next| {
+            if let Value::Block(_) = next.value {
+              next.span.0 == *offset
+            } else {
+              (next.span.0..next.span.1).contains(offset)
+            }
+          })
+        }
+        BreakLocation::Line { file, line } => {
+          let target = Some((file.clone(), *line));
+          next.is_some_and(|next| line_of(next.span) == target)
+            && prev
+              .is_none_or(|prev| line_of(prev.span) != target)
+        }
+        BreakLocation::Function(name) => {
+          entered && frame.name == *name
+        }
+      })
+      .map(|breakpoint| {
+        (breakpoint.id, breakpoint.compiled.clone())
+      })
+      .collect();
+    let span = next.map_or(frame.block.span, |next| next.span);
+    for (id, condition) in hits {
+      let Some(condition) = condition else {
+        return Ok(Some(id));
+      };
+      if self.eval_condition(condition, span)? {
+        return Ok(Some(id));
+      }
+    }
+    Ok(None)
+  }
+
+  /// Runs a condition on top of the current state and restores the
+  /// operand stack afterwards.
+  fn eval_condition(
+    &mut self,
+    condition: BlockSpan,
+    span: (usize, usize),
+  ) -> Result<bool, EvalError> {
+    let stack = self.stack.clone();
+    let depth = self.exec_stack.len();
+    let watching =
+      std::mem::replace(&mut self.debugger.watching, false);
+    self.exec_stack.push(ExecState::Frame(ExecFrame::new(
+      "<condition>".to_string(),
+      condition,
+    )));
+    let mut res = Ok(());
+    while res.is_ok() && depth < self.exec_stack.len() {
+      res = self.step().map(|_| ());
+    }
+    self.exec_stack.truncate(depth);
+    // Definitions in the condition are not reported
+    self.debugger.watching = watching;
+    let res = res.and_then(|_| {
+      self.try_pop().and_then(|value| value.try_bool()).map_err(
+        |e| {
+          self
+            .map_err(format!("Breakpoint condition: {e}"), span)
+        },
+      )
+    });
+    self.stack = stack;
+    res
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::io::Cursor;
+
+  const SRC: &str = "/sq { dup * } def
+0 1 5 {
+  sq +
+} for
+/x 7 def";
+
+  fn new_vm() -> Vm {
+    let mut vm = Vm::new();
+    vm.parse_source("a.txt", Cursor::new(SRC)).unwrap();
+    vm
+  }
+
+  fn next_line(vm: &Vm) -> usize {
+    let frame = vm.get_exec_stack().last().unwrap().as_frame();
+    let span = frame.block.block[frame.ip].span;
+    vm.source_map().lookup(span.0).unwrap().line
+  }
+
+  #[test]
+  fn test_breakpoint() {
+    let mut vm = new_vm();
+    let line = BreakLocation::Line {
+      file: "a.txt".to_string(),
+      line: 3,
+    };
+    let id = vm.add_breakpoint(line, Some("dup 5 <")).unwrap();
+    let mut stops = vec![];
+    while let Pause::Breakpoint(hit) = vm.resume().unwrap() {
+      assert_eq!(hit, id);
+      assert_eq!(next_line(&vm), 3);
+      stops.push(vm.get_stack().to_vec());
+    }
+    // Stops in the iterations with a partial sum less than 5
+    assert_eq!(
+      stops,
+      [vec![Value::Int(0)], vec![Value::Int(1)]]
+    );
+    assert_eq!(vm.get_stack(), [Value::Int(30)]);
+
+    let mut vm = new_vm();
+    let id = vm
+      .add_breakpoint(
+        BreakLocation::Function("sq".into()),
+        None,
+      )
+      .unwrap();
+    assert_eq!(vm.resume(), Ok(Pause::Breakpoint(id)));
+    assert_eq!(
+      vm.get_exec_stack().last().unwrap().as_frame().name,
+      "sq"
+    );
+    assert!(vm.remove_breakpoint(id));
+    let offset = SRC.find("/x").unwrap();
+    let id = vm
+      .add_breakpoint(BreakLocation::Offset(offset + 1), None)
+      .unwrap();
+    assert_eq!(vm.resume(), Ok(Pause::Breakpoint(id)));
+    assert_eq!(next_line(&vm), 5);
+    assert_eq!(vm.resume(), Ok(Pause::Finished));
+  }
+
+  #[test]
+  fn test_first_token() {
+    let mut vm = Vm::new();
+    vm.parse_source("c.txt", Cursor::new("1 2 +\n3 *"))
+      .unwrap();
+    let line = BreakLocation::Line {
+      file: "c.txt".to_string(),
+      line: 1,
+    };
+    let id = vm.add_breakpoint(line, None).unwrap();
+    assert_eq!(vm.resume(), Ok(Pause::Breakpoint(id)));
+    assert!(vm.get_stack().is_empty());
+    assert_eq!(vm.resume(), Ok(Pause::Finished));
+    assert_eq!(vm.get_stack(), [Value::Int(9)]);
+  }
+
+  #[test]
+  fn test_step() {
+    let mut vm = new_vm();
+    for _ in 0..7 {
+      vm.step_into().unwrap();
+    }
+    // Stopped before `for`
+    assert_eq!(vm.step_over(), Ok(Pause::Step));
+    assert_eq!(vm.get_stack(), [Value::Int(30)]);
+    assert_eq!(next_line(&vm), 5);
+
+    let mut vm = new_vm();
+    vm.add_breakpoint(
+      BreakLocation::Function("sq".into()),
+      None,
+    )
+    .unwrap();
+    vm.resume().unwrap();
+    let depth = vm.get_exec_stack().len();
+    assert_eq!(vm.step_out(), Ok(Pause::Step));
+    assert_eq!(vm.get_exec_stack().len(), depth - 1);
+    assert_eq!(vm.get_stack(), [Value::Int(0), Value::Int(1)]);
+  }
+
+  #[test]
+  fn test_step_loop() {
+    let new_vm = || {
+      let mut vm = Vm::new();
+      vm.parse_source("c.txt", Cursor::new("0 1 3 { + } for"))
+        .unwrap();
+      for _ in 0..6 {
+        vm.eval_step().unwrap();
+      }
+      assert_eq!(vm.get_stack(), [Value::Int(1)]);
+      vm
+    };
+    // `eval_step` runs into the next iteration
+    let mut vm = new_vm();
+    vm.eval_step().unwrap();
+    assert_eq!(vm.get_stack(), [Value::Int(3)]);
+    // while the debugger pauses before it
+    let mut vm = new_vm();
+    assert_eq!(vm.step_into(), Ok(Pause::Step));
+    assert_eq!(vm.get_stack(), [Value::Int(1)]);
+    assert_eq!(vm.step_into(), Ok(Pause::Step));
+    assert_eq!(vm.get_stack(), [Value::Int(3)]);
+
+    // An empty body leaves the same indices as without a debugger
+    let mut vm = Vm::new();
+    vm.parse_source("d.txt", Cursor::new("0 3 { } for"))
+      .unwrap();
+    while vm.step_into().unwrap() != Pause::Finished {}
+    assert_eq!(
+      vm.get_stack(),
+      [Value::Int(0), Value::Int(1), Value::Int(2)]
+    );
+  }
+
+  #[test]
+  fn test_condition_error() {
+    let mut vm = new_vm();
+    vm.add_breakpoint(
+      BreakLocation::Function("sq".into()),
+      Some("clear"),
+    )
+    .unwrap();
+    assert_eq!(
+      vm.resume().map_err(|e| e.to_string()),
+      Err(
+        "a.txt:1:7: Breakpoint condition: Stack underflow"
+          .to_string()
+      )
+    );
+    // The script can continue
+    assert_eq!(vm.get_stack(), [Value::Int(0), Value::Int(1)]);
+  }
+
+  #[test]
+  fn test_watchpoint() {
+    let mut vm = Vm::new();
+    vm.parse_source(
+      "b.txt",
+      Cursor::new(
+        "/f { /n 1 def /n 2 def } def\n1 3 { /n exch def } for f",
+      ),
+    )
+    .unwrap();
+    let id = vm.add_watchpoint("n");
+    let mut hits = vec![];
+    while let Pause::Watchpoint(new_hits) = vm.resume().unwrap()
+    {
+      let [hit] = &new_hits[..] else {
+        panic!("{new_hits:?}");
+      };
+      assert_eq!(hit.id, id);
+      let span = hit.span.unwrap();
+      let loc = vm.source_map().lookup(span.0).unwrap();
+      hits.push((
+        hit.old.clone(),
+        hit.new.clone(),
+        loc.to_string(),
+      ));
+    }
+    let int = |i| Value::Int(i);
+    assert_eq!(
+      hits,
+      [
+        (None, int(1), "b.txt:2:15".to_string()),
+        (Some(int(1)), int(2), "b.txt:2:15".to_string()),
+        // Local to `f`
+        (None, int(1), "b.txt:1:11".to_string()),
+        (Some(int(1)), int(2), "b.txt:1:20".to_string()),
+      ]
+    );
+    assert_eq!(vm.root_vars().get("n"), Some(&int(2)));
+  }
+
+  #[test]
+  fn test_watch_host() {
+    let mut vm = Vm::new();
+    vm.add_fn(
+      "reset".to_string(),
+      Box::new(|vm| {
+        vm.set_global("n", Value::Int(0));
+        vm.set_global("n", Value::Int(1));
+      }),
+    );
+    vm.parse_source("c.txt", Cursor::new("reset n")).unwrap();
+    let a = vm.add_watchpoint("n");
+    let b = vm.add_watchpoint("n");
+    vm.set_global("n", Value::Int(5));
+    let hit = |id, old, new| WatchHit {
+      id,
+      name: "n".to_string(),
+      old,
+      new,
+      span: None,
+    };
+    let int = |i| Some(Value::Int(i));
+    // Both watchpoints report every definition in the step, but not
+    // the one by the host before it
+    assert_eq!(
+      vm.resume(),
+      Ok(Pause::Watchpoint(vec![
+        hit(a, int(5), Value::Int(0)),
+        hit(b, int(5), Value::Int(0)),
+        hit(a, int(0), Value::Int(1)),
+        hit(b, int(0), Value::Int(1)),
+      ]))
+    );
+    assert_eq!(vm.resume(), Ok(Pause::Finished));
+    assert_eq!(vm.get_stack(), [Value::Int(1)]);
+  }
+
+  #[test]
+  fn test_watch_eval_step() {
+    let mut vm = Vm::new();
+    let id = vm.add_watchpoint("n");
+    // Running to completion does not collect the definitions
+    vm.eval_source(
+      "c.txt",
+      Cursor::new("0 99 { /n exch def } for"),
+    )
+    .unwrap();
+    assert!(vm.take_watch_hits().is_empty());
+
+    vm.parse_source("d.txt", Cursor::new("/n 1 def 2"))
+      .unwrap();
+    let mut hits = vec![];
+    while vm.eval_step().unwrap().is_some() {
+      hits.push(vm.take_watch_hits());
+    }
+    let start = vm.source_map().files()[1].start;
+    let hit = WatchHit {
+      id,
+      name: "n".to_string(),
+      old: Some(Value::Int(98)),
+      new: Value::Int(1),
+      span: Some((start + 5, start + 8)),
+    };
+    // Each step reports its own definitions
+    assert_eq!(hits[2], [hit]);
+    assert_eq!(
+      hits.iter().filter(|hits| !hits.is_empty()).count(),
+      1
+    );
+  }
+}
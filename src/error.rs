@@ -0,0 +1,291 @@
+use crate::source_map::{Location, SourceMap};
+
+/// The source line containing a span, resolved at the time an
+/// error is raised so that it can be rendered later.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Snippet {
+  line: String,
+  /// The range of columns (0-based, in chars) to underline.
+  start: usize,
+  end: usize,
+}
+
+impl Snippet {
+  fn new(
+    span: (usize, usize),
+    loc: &Location,
+    source_map: &SourceMap,
+  ) -> Option<Self> {
+    let line = source_map.file(span.0)?.line_text(span.0);
+    let start = loc.col - 1;
+    let len = span.1.saturating_sub(span.0).max(1);
+    let end = (start + len).min(line.chars().count());
+    Some(Self {
+      line: line.to_string(),
+      start,
+      end: end.max(start + 1),
+    })
+  }
+}
+
+fn paint(color: bool, code: &str, s: &str) -> String {
+  if color {
+    format!("\x1b[{code}m{s}\x1b[0m")
+  } else {
+    s.to_string()
+  }
+}
+
+/// Renders the message, the location and the underlined source
+/// line of an error.
+fn render_header(
+  color: bool,
+  message: &str,
+  location: Option<&Location>,
+  snippet: Option<&Snippet>,
+) -> String {
+  let mut ret = format!(
+    "{}: {}\n",
+    paint(color, "1;31", "error"),
+    paint(color, "1", message)
+  );
+  if let Some(loc) = location {
+    let line_no = loc.line.to_string();
+    let pad = " ".repeat(line_no.len());
+    ret +=
+      &format!("{pad}{} {loc}\n", paint(color, "34", "-->"));
+    if let Some(snippet) = snippet {
+      let gutter = paint(color, "34", "|");
+      let underline = " ".repeat(snippet.start)
+        + &"^".repeat(snippet.end - snippet.start);
+      ret += &format!("{pad} {gutter}\n");
+      ret += &format!(
+        "{} {gutter} {}\n",
+        paint(color, "34", &line_no),
+        snippet.line
+      );
+      ret += &format!(
+        "{pad} {gutter} {}\n",
+        paint(color, "1;31", &underline)
+      );
+    }
+  }
+  ret
+}
+
+/// A frame of the execution stack at the time of an error.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TraceFrame {
+  pub name: String,
+  /// The location of the token that entered this frame, or `None`
+  /// for the root frame.
+  pub call_site: Option<Location>,
+}
+
+/// An error raised while executing a script, carrying enough
+/// information to render a report without access to the `Vm`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct EvalError {
+  pub message: String,
+  pub span: Option<(usize, usize)>,
+  pub location: Option<Location>,
+  /// Frames from the innermost to the outermost.
+  pub trace: Vec<TraceFrame>,
+  snippet: Option<Box<Snippet>>,
+}
+
+impl EvalError {
+  pub(crate) fn new(
+    message: String,
+    span: Option<(usize, usize)>,
+    source_map: &SourceMap,
+    trace: Vec<TraceFrame>,
+  ) -> Self {
+    let location =
+      span.and_then(|span| source_map.lookup(span.0));
+    let snippet =
+      span.zip(location.as_ref()).and_then(|(span, loc)| {
+        Snippet::new(span, loc, source_map).map(Box::new)
+      });
+    Self {
+      message,
+      span,
+      location,
+      trace,
+      snippet,
+    }
+  }
+
+  /// Renders a report with the offending source line, a caret
+  /// underline and the call stack. ANSI escape sequences are used
+  /// for colours if `color` is true.
+  pub fn render(&self, color: bool) -> String {
+    let mut ret = render_header(
+      color,
+      &self.message,
+      self.location.as_ref(),
+      self.snippet.as_deref(),
+    );
+    for frame in &self.trace {
+      if let Some(call_site) = &frame.call_site {
+        ret += &format!(
+          "  in {} called at {call_site}\n",
+          frame.name
+        );
+      } else {
+        ret += &format!("  in {}\n", frame.name);
+      }
+    }
+    ret
+  }
+}
+
+impl std::fmt::Display for EvalError {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    if let Some(loc) = &self.location {
+      write!(f, "{loc}: ")?;
+    }
+    write!(f, "{}", self.message)
+  }
+}
+
+impl std::error::Error for EvalError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ParseErrorKind {
+  /// A `}` without a matching `{`
+  UnmatchedClose,
+  /// A `{` that is not closed until the end of the source
+  UnclosedBlock,
+  /// A `(` that is not closed until the end of the source
+  UnclosedString,
+  /// The source could not be read, e.g. as it is not valid UTF-8
+  Read(std::io::ErrorKind),
+}
+
+impl std::fmt::Display for ParseErrorKind {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    match self {
+      Self::UnmatchedClose => write!(f, "unmatched \"}}\""),
+      Self::UnclosedBlock => write!(f, "unclosed \"{{\""),
+      Self::UnclosedString => {
+        write!(f, "unclosed string literal")
+      }
+      Self::Read(kind) => {
+        write!(f, "failed to read the source: {kind}")
+      }
+    }
+  }
+}
+
+/// An error in the syntax of the source. The span points to the
+/// unmatched closing brace, or the opener of an unclosed block or
+/// string. An error reading the source has no location.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+  pub kind: ParseErrorKind,
+  pub span: (usize, usize),
+  pub location: Option<Location>,
+  snippet: Option<Snippet>,
+}
+
+impl ParseError {
+  pub(crate) fn new(
+    kind: ParseErrorKind,
+    span: (usize, usize),
+    source_map: &SourceMap,
+  ) -> Self {
+    let location = source_map.lookup(span.0);
+    let snippet = location
+      .as_ref()
+      .and_then(|loc| Snippet::new(span, loc, source_map));
+    Self {
+      kind,
+      span,
+      location,
+      snippet,
+    }
+  }
+
+  /// An error reading a source, which has no location.
+  pub(crate) fn read(e: &std::io::Error) -> Self {
+    Self {
+      kind: ParseErrorKind::Read(e.kind()),
+      span: (0, 0),
+      location: None,
+      snippet: None,
+    }
+  }
+
+  pub fn render(&self, color: bool) -> String {
+    render_header(
+      color,
+      &self.kind.to_string(),
+      self.location.as_ref(),
+      self.snippet.as_ref(),
+    )
+  }
+}
+
+impl std::fmt::Display for ParseError {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    if let Some(loc) = &self.location {
+      write!(f, "{loc}: ")?;
+    }
+    write!(f, "{}", self.kind)
+  }
+}
+
+impl std::error::Error for ParseError {}
+
+/// Either a parse error or an eval error, returned by the methods
+/// that parse and run a source at once.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Error {
+  Parse(ParseError),
+  Eval(EvalError),
+}
+
+impl Error {
+  pub fn render(&self, color: bool) -> String {
+    match self {
+      Self::Parse(e) => e.render(color),
+      Self::Eval(e) => e.render(color),
+    }
+  }
+}
+
+impl From<ParseError> for Error {
+  fn from(e: ParseError) -> Self {
+    Self::Parse(e)
+  }
+}
+
+impl From<EvalError> for Error {
+  fn from(e: EvalError) -> Self {
+    Self::Eval(e)
+  }
+}
+
+impl std::fmt::Display for Error {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    match self {
+      Self::Parse(e) => e.fmt(f),
+      Self::Eval(e) => e.fmt(f),
+    }
+  }
+}
+
+impl std::error::Error for Error {}
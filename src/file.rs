@@ -0,0 +1,345 @@
+//! File operators, which are only available if the host allows a
+//! root directory with [`Vm::set_fs_root`].
+
+use std::{
+  fs::{File, OpenOptions},
+  io::{Read, Write},
+  path::{Path, PathBuf},
+};
+
+use crate::{resolve_path, sync::Lock, Shared, Value, Vm};
+
+/// An open file, shared by the copies of a [`Value::File`].
+#[derive(Clone)]
+pub struct FileHandle {
+  /// The path given by the script
+  pub path: String,
+  file: Shared<Lock<Option<File>>>,
+}
+
+impl PartialEq for FileHandle {
+  fn eq(&self, other: &Self) -> bool {
+    Shared::ptr_eq(&self.file, &other.file)
+  }
+}
+
+impl std::fmt::Debug for FileHandle {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    write!(f, "<File {}>", self.path)
+  }
+}
+
+impl FileHandle {
+  pub fn is_closed(&self) -> bool {
+    self.file.lock().is_none()
+  }
+
+  fn with_file<T>(
+    &self,
+    f: impl FnOnce(&mut File) -> std::io::Result<T>,
+  ) -> Result<T, String> {
+    let mut file = self.file.lock();
+    let file = file.as_mut().ok_or_else(|| {
+      format!("File {:?} is closed", self.path)
+    })?;
+    f(file).map_err(|e| format!("{:?}: {e}", self.path))
+  }
+}
+
+/// Resolves a path of a script in the root directory to the real
+/// path to open. Paths which are absolute or escape the root with
+/// `..`, even through a symbolic link of a directory, are rejected,
+/// and so are symbolic links themselves, which could point outside
+/// of the root by the time they are opened.
+fn sandboxed_path(
+  vm: &Vm,
+  path: &str,
+) -> Result<PathBuf, String> {
+  let root = vm
+    .fs_root
+    .as_ref()
+    .ok_or_else(|| "File access is not allowed".to_string())?;
+  let relative = resolve_path(None, path);
+  let outside =
+    || format!("Path {path:?} is outside of the root");
+  if relative.starts_with('/')
+    || relative.split('/').next() == Some("..")
+    || relative.is_empty()
+  {
+    return Err(outside());
+  }
+  let full = root.join(&relative);
+  let (Some(dir), Some(name)) =
+    (full.parent(), full.file_name())
+  else {
+    return Err(outside());
+  };
+  let root = root
+    .canonicalize()
+    .map_err(|e| format!("Invalid root directory: {e}"))?;
+  // The file may be about to be created, so resolve its directory.
+  let real = dir
+    .canonicalize()
+    .map_err(|e| format!("{path:?}: {e}"))?
+    .join(name);
+  if !real.starts_with(&root) {
+    return Err(outside());
+  }
+  if real
+    .symlink_metadata()
+    .is_ok_and(|meta| meta.file_type().is_symlink())
+  {
+    return Err(format!("Path {path:?} is a symbolic link"));
+  }
+  Ok(real)
+}
+
+fn pop_str(vm: &mut Vm) -> Result<String, String> {
+  match vm.try_pop()? {
+    Value::Str(s) => Ok(s),
+    value => Err(format!(
+      "Expected a string, got {}",
+      value.type_name()
+    )),
+  }
+}
+
+fn pop_file(vm: &mut Vm) -> Result<FileHandle, String> {
+  match vm.try_pop()? {
+    Value::File(file) => Ok(file),
+    value => {
+      Err(format!("Expected a file, got {}", value.type_name()))
+    }
+  }
+}
+
+/// Opens a file checked by [`sandboxed_path`] without following a
+/// symbolic link, which may have replaced it since the check.
+fn open_nofollow(
+  path: &Path,
+  options: &mut OpenOptions,
+) -> std::io::Result<File> {
+  #[cfg(unix)]
+  {
+    use std::os::unix::fs::OpenOptionsExt;
+    options.custom_flags(libc::O_NOFOLLOW);
+  }
+  let file = options.open(path)?;
+  // What was opened, rather than what the path names now
+  if !file.metadata()?.is_file() {
+    return Err(std::io::Error::other("Not a regular file"));
+  }
+  Ok(file)
+}
+
+/// `(path) (mode) file` opens a file, where the mode is one of `r`
+/// (read), `w` (write, truncating) and `a` (append).
+pub(crate) fn file(vm: &mut Vm) -> Result<(), String> {
+  let mode = pop_str(vm)?;
+  let path = pop_str(vm)?;
+  let full = sandboxed_path(vm, &path)?;
+  let mut options = OpenOptions::new();
+  match mode.as_str() {
+    "r" => options.read(true),
+    "w" => options.write(true).create(true).truncate(true),
+    "a" => options.append(true).create(true),
+    _ => return Err(format!("Invalid file mode {mode:?}")),
+  };
+  let file = open_nofollow(&full, &mut options)
+    .map_err(|e| format!("Failed to open {path:?}: {e}"))?;
+  vm.stack.push(Value::File(FileHandle {
+    path,
+    file: Shared::new(Lock::new(Some(file))),
+  }));
+  Ok(())
+}
+
+/// `file n readstring` reads up to `n` bytes and pushes them with
+/// `1` if all of them were read, or `0` if the end of the file was
+/// reached.
+pub(crate) fn readstring(vm: &mut Vm) -> Result<(), String> {
+  let len = vm.try_pop()?.try_int()?;
+  let len = usize::try_from(len)
+    .map_err(|_| format!("Invalid length {len}"))?;
+  let file = pop_file(vm)?;
+  let mut buf = vec![];
+  file.with_file(|file| {
+    file.take(len as u64).read_to_end(&mut buf)
+  })?;
+  let filled = buf.len() == len;
+  vm.stack.push(Value::Str(
+    String::from_utf8_lossy(&buf).into_owned(),
+  ));
+  vm.stack.push(Value::Int(filled as i32));
+  Ok(())
+}
+
+/// `file (text) writestring`
+pub(crate) fn writestring(vm: &mut Vm) -> Result<(), String> {
+  let text = pop_str(vm)?;
+  let file = pop_file(vm)?;
+  file.with_file(|file| file.write_all(text.as_bytes()))
+}
+
+pub(crate) fn closefile(vm: &mut Vm) -> Result<(), String> {
+  let file = pop_file(vm)?;
+  file.file.lock().take();
+  Ok(())
+}
+
+pub(crate) fn deletefile(vm: &mut Vm) -> Result<(), String> {
+  let path = pop_str(vm)?;
+  let full = sandboxed_path(vm, &path)?;
+  std::fs::remove_file(full)
+    .map_err(|e| format!("Failed to delete {path:?}: {e}"))
+}
+
+/// `(path) status` pushes the size of the file in bytes and `1`, or
+/// only `0` if it does not exist.
+pub(crate) fn status(vm: &mut Vm) -> Result<(), String> {
+  let path = pop_str(vm)?;
+  let full = sandboxed_path(vm, &path)?;
+  match std::fs::symlink_metadata(full) {
+    Ok(meta) => {
+      let size = i32::try_from(meta.len()).unwrap_or(i32::MAX);
+      vm.stack.push(Value::Int(size));
+      vm.stack.push(Value::Int(1));
+    }
+    Err(_) => vm.stack.push(Value::Int(0)),
+  }
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::io::Cursor;
+
+  fn run(
+    vm: &mut Vm,
+    src: &str,
+  ) -> Result<Vec<String>, String> {
+    vm.eval_source("a", Cursor::new(src))
+      .map_err(|e| e.to_string())?;
+    let ret =
+      vm.get_stack().iter().map(|v| v.to_string()).collect();
+    vm.stack.clear();
+    Ok(ret)
+  }
+
+  /// A temporary directory removed even if the test fails.
+  struct TempDir(PathBuf);
+
+  impl TempDir {
+    fn new(name: &str) -> Self {
+      let path = std::env::temp_dir()
+        .join(format!("rustack-{name}-{}", std::process::id()));
+      std::fs::create_dir_all(&path).unwrap();
+      Self(path)
+    }
+  }
+
+  impl Drop for TempDir {
+    fn drop(&mut self) {
+      let _ = std::fs::remove_dir_all(&self.0);
+    }
+  }
+
+  #[test]
+  fn test_file() {
+    let dir = TempDir::new("test");
+    let root = &dir.0;
+    let mut vm = Vm::builder().fs_root(root).build();
+    let res = run(
+      &mut vm,
+      "/f (data.csv) (w) file def \
+      f (1,2\n3,4) writestring f closefile \
+      (data.csv) status \
+      /f (data.csv) (r) file def \
+      f 4 readstring f 10 readstring f closefile",
+    );
+    assert_eq!(
+      res,
+      Ok(
+        vec!["7", "1", "1,2\n", "1", "3,4", "0"]
+          .into_iter()
+          .map(String::from)
+          .collect()
+      )
+    );
+    assert_eq!(
+      run(&mut vm, "(data.csv) deletefile (data.csv) status"),
+      Ok(vec!["0".to_string()])
+    );
+    assert_eq!(
+      run(&mut vm, "(../x) (r) file"),
+      Err(
+        "a:1:12: Path \"../x\" is outside of the root"
+          .to_string()
+      )
+    );
+  }
+
+  #[cfg(unix)]
+  #[test]
+  fn test_symlink() {
+    let dir = TempDir::new("symlink");
+    let root = dir.0.join("root");
+    std::fs::create_dir_all(root.join("sub")).unwrap();
+    let link = |target: &str, name: &str| {
+      std::os::unix::fs::symlink(target, root.join(name))
+        .unwrap()
+    };
+    // Dangling, so that opening it for writing would create it
+    link("../outside.txt", "link.txt");
+    link("..", "up");
+    link("sub/a.txt", "inside.txt");
+    let mut vm = Vm::builder().fs_root(&root).build();
+    assert_eq!(
+      run(&mut vm, "(link.txt) (w) file"),
+      Err(
+        "a:1:16: Path \"link.txt\" is a symbolic link"
+          .to_string()
+      )
+    );
+    assert!(!dir.0.join("outside.txt").exists());
+    assert_eq!(
+      run(&mut vm, "(up/x.txt) (w) file"),
+      Err(
+        "a:1:16: Path \"up/x.txt\" is outside of the root"
+          .to_string()
+      )
+    );
+    assert!(!dir.0.join("x.txt").exists());
+    assert_eq!(
+      run(&mut vm, "(inside.txt) status"),
+      Err(
+        "a:1:14: Path \"inside.txt\" is a symbolic link"
+          .to_string()
+      )
+    );
+    // A link swapped in after the check is not followed either
+    std::fs::write(root.join("sub/a.txt"), "a").unwrap();
+    let open = |name: &str| {
+      open_nofollow(
+        &root.join(name),
+        OpenOptions::new().read(true),
+      )
+    };
+    assert!(open("sub/a.txt").is_ok());
+    assert!(open("inside.txt").is_err());
+    assert!(open("sub").is_err());
+  }
+
+  #[test]
+  fn test_no_fs() {
+    let mut vm = Vm::new();
+    assert_eq!(
+      run(&mut vm, "(a.txt) status"),
+      Err("a:1:9: File access is not allowed".to_string())
+    );
+  }
+}
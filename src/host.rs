@@ -0,0 +1,108 @@
+use std::any::Any;
+
+use crate::{sync::AnyObject, MaybeSend, MaybeSync, Shared};
+
+/// An opaque object of the host, which scripts can store and pass
+/// around but only native functions can look into.
+///
+/// ```
+/// use rustack::{HostValue, Shared, Value, Vm};
+///
+/// struct Canvas { width: i32 }
+///
+/// let mut vm = Vm::new();
+/// vm.register("canvas", || HostValue::new("canvas", Canvas { width: 320 }));
+/// vm.register("width", |canvas: Shared<Canvas>| canvas.width);
+/// vm.eval_source("a", std::io::Cursor::new("canvas width")).unwrap();
+/// assert_eq!(vm.get_stack(), [Value::Int(320)]);
+/// ```
+#[derive(Clone)]
+pub struct HostValue {
+  object: Shared<AnyObject>,
+  type_name: &'static str,
+  display: Option<fn(&dyn Any) -> String>,
+}
+
+impl HostValue {
+  /// Wraps an object with the name returned by `type` in scripts.
+  pub fn new<T: Any + MaybeSend + MaybeSync>(
+    type_name: &'static str,
+    object: T,
+  ) -> Self {
+    Self::from_shared(type_name, Shared::new(object))
+  }
+
+  /// Wraps an object shared with the host.
+  pub fn from_shared<T: Any + MaybeSend + MaybeSync>(
+    type_name: &'static str,
+    object: Shared<T>,
+  ) -> Self {
+    Self {
+      object,
+      type_name,
+      display: None,
+    }
+  }
+
+  /// Wraps an object which is printed by `puts` and its friends
+  /// with its `Display` implementation.
+  pub fn with_display<
+    T: Any + MaybeSend + MaybeSync + std::fmt::Display,
+  >(
+    type_name: &'static str,
+    object: T,
+  ) -> Self {
+    Self {
+      display: Some(|object| {
+        object
+          .downcast_ref::<T>()
+          .map_or_else(String::new, |object| object.to_string())
+      }),
+      ..Self::new(type_name, object)
+    }
+  }
+
+  pub fn type_name(&self) -> &'static str {
+    self.type_name
+  }
+
+  /// Returns the object if it is of the type `T`.
+  pub fn downcast_ref<T: Any>(&self) -> Option<&T> {
+    self.object.downcast_ref()
+  }
+
+  /// Returns a shared pointer to the object if it is of the type
+  /// `T`.
+  pub fn downcast<T: Any + MaybeSend + MaybeSync>(
+    &self,
+  ) -> Option<Shared<T>> {
+    self.object.clone().downcast().ok()
+  }
+}
+
+impl PartialEq for HostValue {
+  fn eq(&self, other: &Self) -> bool {
+    Shared::ptr_eq(&self.object, &other.object)
+  }
+}
+
+impl std::fmt::Debug for HostValue {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    write!(f, "{self}")
+  }
+}
+
+impl std::fmt::Display for HostValue {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    match self.display {
+      Some(display) => write!(f, "{}", display(&*self.object)),
+      None => write!(f, "<{}>", self.type_name),
+    }
+  }
+}
@@ -0,0 +1,201 @@
+//! Operators reading the input source of a `Vm`.
+//!
+//! Like PostScript, `readline` and `read` push `1` after the value
+//! they read, or only `0` at the end of the input.
+
+use std::io::{self, BufRead, Read};
+
+use crate::{Value, Vm};
+
+/// The default input of a `Vm`, reading the standard input through
+/// the buffer of std shared by all `Vm`s. A `BufReader` of each
+/// `Vm` would keep the input it read ahead from the others.
+///
+/// It looks ahead at most one byte itself, for `read`.
+#[derive(Debug, Default)]
+pub(crate) struct StdinInput {
+  peeked: [u8; 1],
+  filled: bool,
+}
+
+impl Read for StdinInput {
+  fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+    if buf.is_empty() {
+      return Ok(0);
+    }
+    if std::mem::take(&mut self.filled) {
+      buf[0] = self.peeked[0];
+      return Ok(1);
+    }
+    io::stdin().read(buf)
+  }
+}
+
+impl BufRead for StdinInput {
+  fn fill_buf(&mut self) -> io::Result<&[u8]> {
+    if !self.filled {
+      self.filled = io::stdin().read(&mut self.peeked)? == 1;
+    }
+    Ok(&self.peeked[..usize::from(self.filled)])
+  }
+
+  fn consume(&mut self, amt: usize) {
+    if amt > 0 {
+      self.filled = false;
+    }
+  }
+
+  fn read_until(
+    &mut self,
+    byte: u8,
+    buf: &mut Vec<u8>,
+  ) -> io::Result<usize> {
+    if std::mem::take(&mut self.filled) {
+      buf.push(self.peeked[0]);
+      if self.peeked[0] == byte {
+        return Ok(1);
+      }
+      return Ok(1 + io::stdin().lock().read_until(byte, buf)?);
+    }
+    io::stdin().lock().read_until(byte, buf)
+  }
+
+  fn read_line(
+    &mut self,
+    buf: &mut String,
+  ) -> io::Result<usize> {
+    let mut line = vec![];
+    let len = self.read_until(b'\n', &mut line)?;
+    let line = String::from_utf8(line).map_err(|e| {
+      io::Error::new(io::ErrorKind::InvalidData, e)
+    })?;
+    buf.push_str(&line);
+    Ok(len)
+  }
+}
+
+fn io_error(e: std::io::Error) -> String {
+  format!("Failed to read input: {e}")
+}
+
+/// Pushes the next line without the line terminator.
+pub(crate) fn readline(vm: &mut Vm) -> Result<(), String> {
+  let mut line = String::new();
+  if vm.input.read_line(&mut line).map_err(io_error)? == 0 {
+    vm.stack.push(Value::Int(0));
+    return Ok(());
+  }
+  if line.ends_with('\n') {
+    line.pop();
+    if line.ends_with('\r') {
+      line.pop();
+    }
+  }
+  vm.stack.push(Value::Str(line));
+  vm.stack.push(Value::Int(1));
+  Ok(())
+}
+
+/// Pushes the next whitespace-separated token, as a number if it
+/// can be parsed as one, or as a string otherwise.
+pub(crate) fn read(vm: &mut Vm) -> Result<(), String> {
+  let mut token = vec![];
+  loop {
+    let buf = vm.input.fill_buf().map_err(io_error)?;
+    if buf.is_empty() {
+      break;
+    }
+    let skip = if token.is_empty() {
+      buf.iter().take_while(|b| b.is_ascii_whitespace()).count()
+    } else {
+      0
+    };
+    let len = buf[skip..]
+      .iter()
+      .take_while(|b| !b.is_ascii_whitespace())
+      .count();
+    token.extend_from_slice(&buf[skip..skip + len]);
+    let done = skip + len < buf.len() && !token.is_empty();
+    vm.input.consume(skip + len);
+    if done {
+      break;
+    }
+  }
+  if token.is_empty() {
+    vm.stack.push(Value::Int(0));
+    return Ok(());
+  }
+  let token = String::from_utf8(token)
+    .map_err(|_| "Input is not valid UTF-8".to_string())?;
+  let value = if let Ok(num) = token.parse::<i32>() {
+    Value::Int(num)
+  } else if let Ok(num) = token.parse::<f32>() {
+    Value::Num(num)
+  } else {
+    Value::Str(token)
+  };
+  vm.stack.push(value);
+  vm.stack.push(Value::Int(1));
+  Ok(())
+}
+
+/// Pushes the rest of the input as a string, which is empty at the
+/// end of the input.
+pub(crate) fn readall(vm: &mut Vm) -> Result<(), String> {
+  let mut text = String::new();
+  vm.input.read_to_string(&mut text).map_err(io_error)?;
+  vm.stack.push(Value::Str(text));
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use std::io::Cursor;
+
+  fn run(input: &str, src: &str) -> Vec<Value> {
+    let mut vm = Vm::builder()
+      .input(Cursor::new(input.to_string()))
+      .build();
+    vm.eval_source("a", Cursor::new(src)).unwrap();
+    vm.get_stack().to_vec()
+  }
+
+  #[test]
+  fn test_readline() {
+    use Value::*;
+    assert_eq!(
+      run("ab\r\n\nc", "readline readline readline readline"),
+      vec![
+        Str("ab".to_string()),
+        Int(1),
+        Str("".to_string()),
+        Int(1),
+        Str("c".to_string()),
+        Int(1),
+        Int(0),
+      ]
+    );
+  }
+
+  #[test]
+  fn test_read() {
+    use Value::*;
+    assert_eq!(
+      run(" 12\n 1.5 abc  ", "read read read read"),
+      vec![
+        Int(12),
+        Int(1),
+        Num(1.5),
+        Int(1),
+        Str("abc".to_string()),
+        Int(1),
+        Int(0),
+      ]
+    );
+    assert_eq!(
+      run("1 rest\nof input", "read pop pop readall"),
+      vec![Str(" rest\nof input".to_string())]
+    );
+  }
+}
@@ -0,0 +1,223 @@
+//! Conversion between data values and JSON, enabled by the `serde`
+//! feature.
+//!
+//! Numbers, strings, arrays and dictionaries map to their JSON
+//! counterparts. Names are serialized as strings, so they are not
+//! round-tripped but come back as strings, and booleans are
+//! deserialized as `1` or `0`. `null` has no counterpart, so it is
+//! rejected, and so are the reals NaN and infinity, which JSON
+//! cannot represent. Blocks, operators and the other values which
+//! only make sense inside a running `Vm` cannot be serialized.
+
+use std::{collections::BTreeMap, fmt};
+
+use serde::{
+  de::{self, MapAccess, SeqAccess, Visitor},
+  ser::{self, SerializeMap, SerializeSeq},
+  Deserialize, Deserializer, Serialize, Serializer,
+};
+
+use crate::{Value, Vm};
+
+impl Serialize for Value {
+  fn serialize<S: Serializer>(
+    &self,
+    serializer: S,
+  ) -> Result<S::Ok, S::Error> {
+    match self {
+      Self::Int(i) => serializer.serialize_i32(*i),
+      Self::Num(f) if f.is_finite() => {
+        serializer.serialize_f32(*f)
+      }
+      Self::Num(f) => Err(ser::Error::custom(format!(
+        "Cannot serialize {f} in JSON"
+      ))),
+      Self::Str(s) | Self::Sym(s) => {
+        serializer.serialize_str(s)
+      }
+      Self::Array(items) => {
+        let mut seq =
+          serializer.serialize_seq(Some(items.len()))?;
+        for item in items {
+          seq.serialize_element(item)?;
+        }
+        seq.end()
+      }
+      Self::Dict(entries) => {
+        let mut map =
+          serializer.serialize_map(Some(entries.len()))?;
+        for (key, value) in entries {
+          map.serialize_entry(key, value)?;
+        }
+        map.end()
+      }
+      _ => Err(ser::Error::custom(format!(
+        "Cannot serialize a value of {}",
+        self.type_name()
+      ))),
+    }
+  }
+}
+
+struct ValueVisitor;
+
+impl<'de> Visitor<'de> for ValueVisitor {
+  type Value = Value;
+
+  fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "a number, a string, an array or a map")
+  }
+
+  fn visit_bool<E: de::Error>(
+    self,
+    v: bool,
+  ) -> Result<Value, E> {
+    Ok(Value::Int(v as i32))
+  }
+
+  fn visit_unit<E: de::Error>(self) -> Result<Value, E> {
+    Err(E::custom("null has no counterpart"))
+  }
+
+  fn visit_i64<E: de::Error>(self, v: i64) -> Result<Value, E> {
+    Ok(
+      i32::try_from(v).map_or(Value::Num(v as f32), Value::Int),
+    )
+  }
+
+  fn visit_u64<E: de::Error>(self, v: u64) -> Result<Value, E> {
+    Ok(
+      i32::try_from(v).map_or(Value::Num(v as f32), Value::Int),
+    )
+  }
+
+  fn visit_f64<E: de::Error>(self, v: f64) -> Result<Value, E> {
+    Ok(Value::Num(v as f32))
+  }
+
+  fn visit_str<E: de::Error>(
+    self,
+    v: &str,
+  ) -> Result<Value, E> {
+    Ok(Value::Str(v.to_string()))
+  }
+
+  fn visit_string<E: de::Error>(
+    self,
+    v: String,
+  ) -> Result<Value, E> {
+    Ok(Value::Str(v))
+  }
+
+  fn visit_seq<A: SeqAccess<'de>>(
+    self,
+    mut seq: A,
+  ) -> Result<Value, A::Error> {
+    let mut items = vec![];
+    while let Some(item) = seq.next_element()? {
+      items.push(item);
+    }
+    Ok(Value::Array(items))
+  }
+
+  fn visit_map<A: MapAccess<'de>>(
+    self,
+    mut map: A,
+  ) -> Result<Value, A::Error> {
+    let mut entries = BTreeMap::new();
+    while let Some((key, value)) = map.next_entry()? {
+      entries.insert(key, value);
+    }
+    Ok(Value::Dict(entries))
+  }
+}
+
+impl<'de> Deserialize<'de> for Value {
+  fn deserialize<D: Deserializer<'de>>(
+    deserializer: D,
+  ) -> Result<Self, D::Error> {
+    deserializer.deserialize_any(ValueVisitor)
+  }
+}
+
+/// `string json-parse` -> `value`
+pub(crate) fn json_parse(vm: &mut Vm) -> Result<(), String> {
+  let value = vm.try_pop()?;
+  let Value::Str(s) = &value else {
+    return Err(format!(
+      "Expected a string, got {}",
+      value.type_name()
+    ));
+  };
+  let value = serde_json::from_str(s)
+    .map_err(|e| format!("Invalid JSON: {e}"))?;
+  vm.stack.push(value);
+  Ok(())
+}
+
+/// `value json-stringify` -> `string`
+pub(crate) fn json_stringify(
+  vm: &mut Vm,
+) -> Result<(), String> {
+  let value = vm.try_pop()?;
+  let s =
+    serde_json::to_string(&value).map_err(|e| e.to_string())?;
+  vm.stack.push(Value::Str(s));
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test::run;
+
+  #[test]
+  fn test_round_trip() {
+    let json = r#"{"a":[1,2.5,"x"],"b":{"c":-3}}"#;
+    let value: Value = serde_json::from_str(json).unwrap();
+    assert_eq!(serde_json::to_string(&value).unwrap(), json);
+    assert_eq!(
+      serde_json::from_str::<Value>("[true, null]")
+        .map_err(|e| e.to_string()),
+      Err(
+        "null has no counterpart at line 1 column 11"
+          .to_string()
+      )
+    );
+  }
+
+  #[test]
+  fn test_builtins() {
+    assert_eq!(
+      run("<< /x [ 1 /y ] >> json-stringify"),
+      Ok(vec![Value::Str(r#"{"x":[1,"y"]}"#.to_string())])
+    );
+    assert_eq!(
+      run("(x) json-parse (1) json-parse"),
+      Err(
+        "<input>:1:5: Invalid JSON: expected value at line 1 \
+        column 1"
+          .to_string()
+      )
+    );
+    // Names come back as strings
+    assert_eq!(
+      run("/y json-stringify json-parse type"),
+      Ok(vec![Value::Sym("stringtype".to_string())])
+    );
+    assert_eq!(
+      run("1.0 0 div json-stringify"),
+      Err(
+        "<input>:1:11: Cannot serialize inf in JSON"
+          .to_string()
+      )
+    );
+    assert_eq!(
+      run("[ { 1 } ] json-stringify"),
+      Err(
+        "<input>:1:11: Cannot serialize a value of blocktype"
+          .to_string()
+      )
+    );
+  }
+}
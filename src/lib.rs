@@ -1,16 +1,147 @@
-use std::{collections::HashMap, io::BufRead, rc::Rc};
+mod array;
+mod builder;
+mod builtins;
+mod convert;
+mod debugger;
+mod error;
+mod file;
+mod host;
+mod input;
+#[cfg(feature = "serde")]
+mod json;
+mod loader;
+mod print;
+mod snapshot;
+mod source_map;
+mod stack;
+mod sync;
+mod types;
+
+use std::{
+  collections::{BTreeMap, HashMap},
+  io::{BufRead, Cursor, Write},
+  path::PathBuf,
+};
+
+pub use crate::{
+  builder::{OutputBuffer, VmBuilder},
+  builtins::{BuiltinGroup, OperatorInfo, Sandbox},
+  convert::{
+    FromValue, IntoResults, IntoValue, NativeFunction,
+  },
+  debugger::{
+    BreakLocation, Breakpoint, Pause, WatchHit, Watchpoint,
+  },
+  error::{
+    Error, EvalError, ParseError, ParseErrorKind, TraceFrame,
+  },
+  file::FileHandle,
+  host::HostValue,
+  loader::{resolve_path, FileLoader, FsLoader, MemoryLoader},
+  snapshot::{SnapshotError, SNAPSHOT_VERSION},
+  source_map::{Location, SourceFile, SourceMap},
+  sync::{
+    DynBufRead, DynWrite, HostFn, MaybeSend, MaybeSync, Shared,
+  },
+};
+use crate::{
+  input::StdinInput,
+  sync::{DynFileLoader, NativeFn},
+};
 
 #[derive(Debug, Clone, PartialEq)]
-pub enum Value<'f> {
+pub enum Value {
   Int(i32),
   Num(f32),
   Op(String),
   Sym(String),
-  Block(BlockSpan<'f>),
-  Native(NativeOp<'f>),
+  Str(String),
+  Block(BlockSpan),
+  Native(NativeOp),
+  /// A marker pushed by `mark`
+  Mark,
+  /// A module bound by `import`, referring to its resolved path.
+  Module(String),
+  /// A file opened by `file`
+  File(FileHandle),
+  /// An object of the host
+  Host(HostValue),
+  /// An array built by `[ ... ]`
+  Array(Vec<Value>),
+  /// A dictionary built by `<< key value ... >>`, sorted by key
+  Dict(BTreeMap<String, Value>),
 }
 
-impl<'f> Value<'f> {
+impl Value {
+  /// The name pushed by the `type` operator, e.g. `integertype`.
+  pub fn type_name(&self) -> &'static str {
+    match self {
+      Self::Int(_) => "integertype",
+      Self::Num(_) => "realtype",
+      Self::Op(_) | Self::Sym(_) => "nametype",
+      Self::Str(_) => "stringtype",
+      Self::Block(_) => "blocktype",
+      Self::Native(_) => "operatortype",
+      Self::Mark => "marktype",
+      Self::File(_) => "filetype",
+      Self::Host(host) => host.type_name(),
+      Self::Module(_) => "moduletype",
+      Self::Array(_) => "arraytype",
+      Self::Dict(_) => "dicttype",
+    }
+  }
+
+  fn type_error(&self, expected: &str) -> String {
+    format!("Expected {expected}, got {}", self.type_name())
+  }
+
+  /// Returns the integer value, accepting a real only if it has no
+  /// fractional part.
+  pub fn try_int(&self) -> Result<i32, String> {
+    match self {
+      Self::Int(val) => Ok(*val),
+      Self::Num(val)
+        if val.fract() == 0.
+          && (i32::MIN as f32..=i32::MAX as f32)
+            .contains(val) =>
+      {
+        Ok(*val as i32)
+      }
+      _ => Err(self.type_error("an integer")),
+    }
+  }
+
+  pub fn try_num(&self) -> Result<f32, String> {
+    match self {
+      Self::Int(val) => Ok(*val as f32),
+      Self::Num(val) => Ok(*val),
+      _ => Err(self.type_error("a number")),
+    }
+  }
+
+  pub fn try_bool(&self) -> Result<bool, String> {
+    Ok(self.try_num()? != 0.)
+  }
+
+  pub fn try_sym(&self) -> Result<&str, String> {
+    if let Self::Sym(sym) = self {
+      Ok(sym)
+    } else {
+      Err(self.type_error("a symbol"))
+    }
+  }
+
+  pub fn try_block(self) -> Result<BlockSpan, String> {
+    match self {
+      Self::Block(val) => Ok(val),
+      _ => Err(self.type_error("a block")),
+    }
+  }
+
+  /// Panics if the value is of another type.
+  #[deprecated(
+    note = "use `try_int`, which returns an error instead"
+  )]
   pub fn as_int(&self) -> i32 {
     match self {
       Self::Int(val) => *val,
@@ -19,6 +150,10 @@ impl<'f> Value<'f> {
     }
   }
 
+  /// Panics if the value is of another type.
+  #[deprecated(
+    note = "use `try_num`, which returns an error instead"
+  )]
   pub fn as_num(&self) -> f32 {
     match self {
       Self::Int(val) => *val as f32,
@@ -27,17 +162,31 @@ impl<'f> Value<'f> {
     }
   }
 
+  /// Panics if the value is of another type.
+  #[deprecated(
+    note = "use `try_bool`, which returns an error instead"
+  )]
   pub fn as_bool(&self) -> bool {
-    self.as_int() != 0
+    #[allow(deprecated)]
+    let int = self.as_int();
+    int != 0
   }
 
-  pub fn to_block(self) -> BlockSpan<'f> {
+  /// Panics if the value is of another type.
+  #[deprecated(
+    note = "use `try_block`, which returns an error instead"
+  )]
+  pub fn to_block(self) -> BlockSpan {
     match self {
       Self::Block(val) => val,
       _ => panic!("Value is not a block"),
     }
   }
 
+  /// Panics if the value is of another type.
+  #[deprecated(
+    note = "use `try_sym`, which returns an error instead"
+  )]
   pub fn as_sym(&self) -> &str {
     if let Self::Sym(sym) = self {
       sym
@@ -47,32 +196,52 @@ impl<'f> Value<'f> {
   }
 }
 
-impl<'f> ToString for Value<'f> {
+impl ToString for Value {
   fn to_string(&self) -> String {
     match self {
       Self::Int(i) => i.to_string(),
       Self::Num(i) => i.to_string(),
-      Self::Op(ref s) | Self::Sym(ref s) => s.clone(),
+      Self::Op(ref s) | Self::Sym(ref s) | Self::Str(ref s) => {
+        s.clone()
+      }
       Self::Block(block) => {
         format!("<Block [{},{}]>", block.span.0, block.span.1)
       }
       Self::Native(_) => "<Native>".to_string(),
+      Self::Mark => "-mark-".to_string(),
+      Self::Module(path) => format!("<Module {path}>"),
+      Self::File(file) => format!("<File {}>", file.path),
+      Self::Host(host) => host.to_string(),
+      Self::Array(items) => {
+        let items: Vec<_> =
+          items.iter().map(|item| item.to_string()).collect();
+        format!("[{}]", items.join(" "))
+      }
+      Self::Dict(entries) => {
+        let entries: Vec<_> = entries
+          .iter()
+          .map(|(key, value)| {
+            format!("/{key} {}", value.to_string())
+          })
+          .collect();
+        format!("<<{}>>", entries.join(" "))
+      }
     }
   }
 }
 
 #[derive(Clone)]
-pub struct NativeOp<'f>(Rc<Box<dyn Fn(&mut Vm) + 'f>>);
+pub struct NativeOp(Shared<Box<NativeFn>>);
 
-impl<'f> PartialEq for NativeOp<'f> {
-  fn eq(&self, other: &NativeOp<'f>) -> bool {
-    Rc::as_ptr(&self.0) == Rc::as_ptr(&other.0)
+impl PartialEq for NativeOp {
+  fn eq(&self, other: &NativeOp) -> bool {
+    Shared::ptr_eq(&self.0, &other.0)
   }
 }
 
-impl<'f> Eq for NativeOp<'f> {}
+impl Eq for NativeOp {}
 
-impl<'f> std::fmt::Debug for NativeOp<'f> {
+impl std::fmt::Debug for NativeOp {
   fn fmt(
     &self,
     f: &mut std::fmt::Formatter<'_>,
@@ -81,213 +250,955 @@ impl<'f> std::fmt::Debug for NativeOp<'f> {
   }
 }
 
+/// A stack effect declaration of a function, written like
+/// `( x y -- len )` in the source.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StackEffect {
+  pub inputs: Vec<String>,
+  pub outputs: Vec<String>,
+}
+
+impl StackEffect {
+  pub fn parse(src: &str) -> Result<Self, String> {
+    let src = src.trim();
+    let src = src
+      .strip_prefix('(')
+      .and_then(|src| src.strip_suffix(')'))
+      .unwrap_or(src);
+    let words: Vec<_> = src.split_whitespace().collect();
+    let mut parts = words.split(|word| *word == "--");
+    let (Some(inputs), Some(outputs), None) =
+      (parts.next(), parts.next(), parts.next())
+    else {
+      return Err(format!(
+        "Stack effect {src:?} should have exactly one \"--\""
+      ));
+    };
+    let to_vec = |words: &[&str]| {
+      words.iter().map(|word| word.to_string()).collect()
+    };
+    Ok(Self {
+      inputs: to_vec(inputs),
+      outputs: to_vec(outputs),
+    })
+  }
+}
+
+impl std::fmt::Display for StackEffect {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    write!(f, "(")?;
+    for input in &self.inputs {
+      write!(f, " {input}")?;
+    }
+    write!(f, " --")?;
+    for output in &self.outputs {
+      write!(f, " {output}")?;
+    }
+    write!(f, " )")
+  }
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub struct ValueSpan<'f> {
-  value: Value<'f>,
+pub struct ValueSpan {
+  value: Value,
   span: (usize, usize),
 }
 
-#[derive(Debug)]
-pub struct ExecFrame<'f> {
+#[derive(Debug, Clone)]
+pub struct ExecFrame {
   pub name: String,
-  block: BlockSpan<'f>,
+  block: BlockSpan,
   ip: usize,
-  pub vars: HashMap<String, Value<'f>>,
+  pub vars: HashMap<String, Value>,
+  /// The height of the operand stack below the arguments, recorded
+  /// if the block has a stack effect to check on return.
+  stack_base: Option<usize>,
+  /// Whether this frame runs the top level of a source. Definitions
+  /// in it go to the root variables of the `Vm` instead of `vars`.
+  root: bool,
+  /// The module which defines the block, so that module functions
+  /// can see its private names.
+  module: Option<Shared<Module>>,
 }
 
-impl<'f> ExecFrame<'f> {
-  fn new(name: String, block: BlockSpan<'f>) -> Self {
+impl ExecFrame {
+  fn new(name: String, block: BlockSpan) -> Self {
     Self {
       name,
       block,
       ip: 0,
       vars: HashMap::new(),
+      stack_base: None,
+      root: false,
+      module: None,
     }
   }
+
+  pub fn is_root(&self) -> bool {
+    self.root
+  }
+
+  /// Returns the span of the token that is executed last in this
+  /// frame, or the span of the whole block if none.
+  pub fn current_span(&self) -> (usize, usize) {
+    self
+      .ip
+      .checked_sub(1)
+      .and_then(|ip| self.block.block.get(ip))
+      .map(|value| value.span)
+      .unwrap_or(self.block.span)
+  }
 }
 
-#[derive(Debug)]
-pub enum ExecState<'f> {
-  Frame(ExecFrame<'f>),
+#[derive(Debug, Clone)]
+pub enum ExecState {
+  Frame(ExecFrame),
   IfCond {
-    frame: ExecFrame<'f>,
-    true_branch: BlockSpan<'f>,
-    false_branch: BlockSpan<'f>,
+    frame: ExecFrame,
+    true_branch: BlockSpan,
+    false_branch: BlockSpan,
   },
-  IfTrue(ExecFrame<'f>),
-  IfFalse(ExecFrame<'f>),
+  IfTrue(ExecFrame),
+  IfFalse(ExecFrame),
   For {
-    frame: ExecFrame<'f>,
+    frame: ExecFrame,
     i: i32,
     end: i32,
   },
+  /// A file loaded by `include` or `run`, named by its resolved
+  /// path. Definitions in it go to the enclosing frame.
+  Include(ExecFrame),
+  /// A module being loaded by `import`, named by its resolved path.
+  /// Its definitions are kept in the `Vm` after loading.
+  Module {
+    frame: ExecFrame,
+    import: Import,
+    exports: Vec<String>,
+  },
 }
 
-impl<'f> ExecState<'f> {
-  pub fn as_frame(&self) -> &ExecFrame<'f> {
+impl ExecState {
+  pub fn as_frame(&self) -> &ExecFrame {
     match self {
       Self::Frame(frame) => frame,
       Self::IfCond { frame, .. } => frame,
       Self::IfTrue(frame) | Self::IfFalse(frame) => frame,
       Self::For { frame, .. } => frame,
+      Self::Include(frame) => frame,
+      Self::Module { frame, .. } => frame,
     }
   }
 
-  fn as_frame_mut(&mut self) -> &mut ExecFrame<'f> {
+  fn as_frame_mut(&mut self) -> &mut ExecFrame {
     match self {
       Self::Frame(frame) => frame,
       Self::IfCond { frame, .. } => frame,
       Self::IfTrue(frame) | Self::IfFalse(frame) => frame,
       Self::For { frame, .. } => frame,
+      Self::Include(frame) => frame,
+      Self::Module { frame, .. } => frame,
     }
   }
 }
 
+/// How the exported names of a module are bound in the importer.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Import {
+  /// Bind the module under a prefix, like `geom.vec2sqlen`.
+  Prefix(String),
+  /// Bind the given names directly.
+  Names(Vec<String>),
+}
+
+/// The definitions of a loaded module.
+#[derive(Debug, Clone)]
+pub struct Module {
+  pub vars: HashMap<String, Value>,
+  pub exports: Vec<String>,
+}
+
 #[derive(Debug, Clone, PartialEq)]
-pub struct BlockSpan<'f> {
-  block: Vec<ValueSpan<'f>>,
+pub struct BlockSpan {
+  /// Shared so that copies of a block, e.g. in forks of a `Vm`, do
+  /// not copy its code
+  block: Shared<Vec<ValueSpan>>,
   span: (usize, usize),
+  effect: Option<StackEffect>,
 }
 
-impl<'f> BlockSpan<'f> {
+impl BlockSpan {
   fn new(start: usize) -> Self {
     Self {
-      block: vec![],
+      block: Shared::new(vec![]),
       span: (start, 0),
+      effect: None,
     }
   }
+
+  pub fn effect(&self) -> Option<&StackEffect> {
+    self.effect.as_ref()
+  }
 }
 
-pub struct Vm<'f> {
-  stack: Vec<Value<'f>>,
-  globals: HashMap<String, Value<'f>>,
-  exec_stack: Vec<ExecState<'f>>,
-  blocks: Vec<BlockSpan<'f>>,
+/// The standard library written in rustack, loaded by
+/// [`Vm::with_prelude`].
+const PRELUDE: &[(&str, &str)] = &[
+  ("<prelude>/math.txt", include_str!("prelude/math.txt")),
+  (
+    "<prelude>/control.txt",
+    include_str!("prelude/control.txt"),
+  ),
+];
+
+pub struct Vm {
+  stack: Vec<Value>,
+  globals: HashMap<String, Value>,
+  /// Variables defined at the top level of sources, which persist
+  /// across `parse_batch` calls.
+  root_vars: HashMap<String, Value>,
+  exec_stack: Vec<ExecState>,
+  blocks: Vec<BlockSpan>,
+  source_map: SourceMap,
+  file_loader: Option<Shared<DynFileLoader>>,
+  /// Compiled files by resolved path
+  loaded_files: HashMap<String, BlockSpan>,
+  /// Loaded modules by resolved path
+  modules: HashMap<String, Shared<Module>>,
+  /// Check declared stack effects on each call and return.
+  debug: bool,
+  /// The directory which file operators can access, if allowed
+  fs_root: Option<PathBuf>,
+  /// The source of the input operators like `readline`
+  input: Box<DynBufRead>,
+  /// The sink of the printing operators
+  output: Box<DynWrite>,
+  /// The sink of diagnostics which are not errors of a script
+  error_output: Box<DynWrite>,
+  /// The state of the generator of `rand`
+  random_state: u32,
+  /// The groups of registered builtins by their names
+  builtin_groups: HashMap<String, BuiltinGroup>,
+  sandbox: Sandbox,
+  debugger: debugger::Debugger,
 }
 
-impl<'f> Vm<'f> {
+impl Vm {
   pub fn new() -> Self {
-    let functions: &[(&str, fn(&mut Vm))] = &[
-      ("+", add),
-      ("-", sub),
-      ("*", mul),
-      ("div", div),
-      ("<", lt),
-      ("or", op_or),
-      ("and", op_and),
-      ("if", op_if),
-      ("for", op_for),
-      ("def", op_def),
-      ("puts", puts),
-      ("pop", pop),
-      ("dup", dup),
-      ("exch", exch),
-      ("index", index),
-      ("load", load),
-      ("sin", sin),
-      ("cos", cos),
-      ("pi", |vm| {
-        vm.stack.push(Value::Num(std::f32::consts::PI))
-      }),
-    ];
-    Self {
+    let mut vm = Self {
       stack: vec![],
-      globals: functions
-        .into_iter()
-        .map(|(name, fun)| {
-          (
-            name.to_string(),
-            Value::Native(NativeOp(Rc::new(Box::new(fun)))),
-          )
-        })
-        .collect(),
+      globals: HashMap::new(),
+      root_vars: HashMap::new(),
       exec_stack: vec![],
       blocks: vec![BlockSpan::new(0)],
+      source_map: SourceMap::new(),
+      file_loader: None,
+      loaded_files: HashMap::new(),
+      modules: HashMap::new(),
+      debug: cfg!(debug_assertions),
+      fs_root: None,
+      input: Box::<StdinInput>::default(),
+      output: Box::new(std::io::stdout()),
+      error_output: Box::new(std::io::stderr()),
+      random_state: 1,
+      builtin_groups: HashMap::new(),
+      sandbox: Sandbox::default(),
+      debugger: Default::default(),
+    };
+    for group in BuiltinGroup::ALL {
+      for (name, fun) in group.builtins() {
+        vm.add_builtin(group, name, Shared::new(Box::new(fun)));
+      }
+    }
+    vm
+  }
+
+  /// Replaces the sandbox, removing and renaming the builtins
+  /// registered so far as it says.
+  pub(crate) fn set_sandbox(&mut self, sandbox: Sandbox) {
+    self.sandbox = sandbox;
+    let builtins: Vec<_> =
+      self.builtin_groups.drain().collect();
+    for (name, group) in builtins {
+      let Some(value) = self.globals.remove(&name) else {
+        continue;
+      };
+      if let Some(renamed) = self.sandbox.name_of(group, &name)
+      {
+        let renamed = renamed.to_string();
+        self.builtin_groups.insert(renamed.clone(), group);
+        self.globals.insert(renamed, value);
+      }
+    }
+  }
+
+  pub fn builder() -> VmBuilder {
+    VmBuilder::new()
+  }
+
+  /// Creates a copy of this `Vm` with the same definitions, loaded
+  /// sources and state, e.g. to run each request from a template
+  /// `Vm`. Native functions, host objects, sources and the code of
+  /// blocks are shared, not copied.
+  ///
+  /// The input and outputs cannot be shared, so the fork uses the
+  /// standard ones. Use [`Vm::fork_with`] to give it others.
+  pub fn fork(&self) -> Self {
+    self.fork_with(
+      Box::<StdinInput>::default(),
+      Box::new(std::io::stdout()),
+      Box::new(std::io::stderr()),
+    )
+  }
+
+  /// Like [`Vm::fork`], but with the given input and outputs, e.g.
+  /// those of a request.
+  pub fn fork_with(
+    &self,
+    input: Box<DynBufRead>,
+    output: Box<DynWrite>,
+    error_output: Box<DynWrite>,
+  ) -> Self {
+    Self {
+      stack: self.stack.clone(),
+      globals: self.globals.clone(),
+      root_vars: self.root_vars.clone(),
+      exec_stack: self.exec_stack.clone(),
+      blocks: self.blocks.clone(),
+      source_map: self.source_map.clone(),
+      file_loader: self.file_loader.clone(),
+      loaded_files: self.loaded_files.clone(),
+      modules: self.modules.clone(),
+      debug: self.debug,
+      fs_root: self.fs_root.clone(),
+      input,
+      output,
+      error_output,
+      random_state: self.random_state,
+      builtin_groups: self.builtin_groups.clone(),
+      sandbox: self.sandbox.clone(),
+      debugger: self.debugger.clone(),
+    }
+  }
+
+  /// Creates a `Vm` with the functions defined in the standard
+  /// prelude, like `square` and `max`. Use [`Vm::new`] instead to
+  /// opt out.
+  ///
+  /// The recursive `for` of `scripts/for.txt` is provided as
+  /// `recfor`, since `for` is a builtin.
+  pub fn with_prelude() -> Self {
+    let mut vm = Self::new();
+    vm.load_prelude();
+    vm
+  }
+
+  /// Runs the standard prelude and registers its definitions as
+  /// globals, so they can be shadowed by scripts like builtins.
+  ///
+  /// The prelude runs in its own unit with empty stacks, so a
+  /// paused execution and the operand stack are left untouched.
+  ///
+  /// It panics if the builtins it uses to define its functions, like
+  /// `defn`, were removed or its names protected, which
+  /// [`VmBuilder::prelude`] avoids by loading it before applying the
+  /// sandbox.
+  pub fn load_prelude(&mut self) {
+    let stack = std::mem::take(&mut self.stack);
+    let exec_stack = std::mem::take(&mut self.exec_stack);
+    let root_vars = std::mem::take(&mut self.root_vars);
+    for (name, src) in PRELUDE {
+      if let Err(e) = self.eval_source(name, Cursor::new(src)) {
+        panic!("Prelude failed: {}", e.render(false));
+      }
     }
+    self.stack = stack;
+    self.exec_stack = exec_stack;
+    let defs =
+      std::mem::replace(&mut self.root_vars, root_vars);
+    self.globals.extend(defs);
+  }
+
+  /// Enable or disable checking of stack effects declared by
+  /// `defn`. It is enabled by default in debug builds.
+  pub fn set_debug(&mut self, debug: bool) {
+    self.debug = debug;
   }
 
-  pub fn get_stack(&self) -> &[Value<'f>] {
+  pub fn get_stack(&self) -> &[Value] {
     &self.stack
   }
 
-  pub fn pop(&mut self) -> Option<Value<'f>> {
+  pub fn push(&mut self, value: Value) {
+    self.stack.push(value);
+  }
+
+  pub fn pop(&mut self) -> Option<Value> {
     self.stack.pop()
   }
 
-  pub fn get_exec_stack(&self) -> &[ExecState<'f>] {
+  /// Pops a value, or returns an error if the stack is empty.
+  pub fn try_pop(&mut self) -> Result<Value, String> {
+    self
+      .stack
+      .pop()
+      .ok_or_else(|| "Stack underflow".to_string())
+  }
+
+  pub fn get_exec_stack(&self) -> &[ExecState] {
     &self.exec_stack
   }
 
-  pub fn add_fn(
+  pub fn source_map(&self) -> &SourceMap {
+    &self.source_map
+  }
+
+  /// Sets the loader of the files for `include` and `run`. Scripts
+  /// cannot load files until it is set.
+  pub fn set_file_loader(
     &mut self,
-    name: String,
-    f: Box<dyn Fn(&mut Vm) + 'f>,
+    loader: Box<DynFileLoader>,
   ) {
+    self.file_loader = Some(loader.into());
+  }
+
+  /// Allows the file operators like `file` to access the files
+  /// under `root`, or disallows them with `None`, which is the
+  /// default.
+  pub fn set_fs_root(&mut self, root: Option<PathBuf>) {
+    self.fs_root = root;
+  }
+
+  /// Sets the source of the input operators like `readline`.
+  pub fn set_input(&mut self, input: Box<DynBufRead>) {
+    self.input = input;
+  }
+
+  /// Sets the sink of the printing operators like `puts`.
+  pub fn set_output(&mut self, output: Box<DynWrite>) {
+    self.output = output;
+  }
+
+  pub fn set_error_output(&mut self, output: Box<DynWrite>) {
+    self.error_output = output;
+  }
+
+  /// Writes a text to the output sink.
+  pub fn write_output(
+    &mut self,
+    text: &str,
+  ) -> Result<(), String> {
     self
+      .output
+      .write_all(text.as_bytes())
+      .map_err(|e| format!("Failed to write output: {e}"))
+  }
+
+  /// Registers a builtin in a group, like drawing operators in
+  /// [`BuiltinGroup::Canvas`], unless the sandbox excludes it.
+  pub fn add_builtin_fn(
+    &mut self,
+    group: BuiltinGroup,
+    name: &str,
+    f: Box<HostFn>,
+  ) {
+    self.add_builtin(
+      group,
+      name,
+      Shared::new(Box::new(move |vm| {
+        f(vm);
+        Ok(())
+      })),
+    );
+  }
+
+  /// Registers a Rust function as an operator. Its arguments are
+  /// popped and converted with [`FromValue`], with the last one on
+  /// the top of the stack, and its results are pushed.
+  ///
+  /// The function cannot borrow from the host, since scripts can
+  /// keep it in a variable for as long as the `Vm` lives. Share the
+  /// state with [`Shared`] and a cell instead.
+  ///
+  /// ```
+  /// use rustack::{Value, Vm};
+  ///
+  /// let mut vm = Vm::new();
+  /// vm.register("hypot", |x: f64, y: f64| x.hypot(y));
+  /// vm.eval_source("a", std::io::Cursor::new("3 4 hypot")).unwrap();
+  /// assert_eq!(vm.get_stack(), [Value::Num(5.)]);
+  /// ```
+  pub fn register<Args>(
+    &mut self,
+    name: &str,
+    f: impl NativeFunction<Args>,
+  ) {
+    self.globals.insert(
+      name.to_string(),
+      Value::Native(NativeOp(f.into_native(name))),
+    );
+  }
+
+  /// Like [`Vm::register`], but in a builtin group like
+  /// [`Vm::add_builtin_fn`].
+  pub fn register_builtin<Args>(
+    &mut self,
+    group: BuiltinGroup,
+    name: &str,
+    f: impl NativeFunction<Args>,
+  ) {
+    let Some(renamed) = self.sandbox.name_of(group, name)
+    else {
+      return;
+    };
+    let native = f.into_native(renamed);
+    self.add_builtin(group, name, native);
+  }
+
+  fn add_builtin(
+    &mut self,
+    group: BuiltinGroup,
+    name: &str,
+    f: Shared<Box<NativeFn>>,
+  ) {
+    let Some(name) = self.sandbox.name_of(group, name) else {
+      return;
+    };
+    let name = name.to_string();
+    self.builtin_groups.insert(name.clone(), group);
+    self.globals.insert(name, Value::Native(NativeOp(f)));
+  }
+
+  pub fn sandbox(&self) -> &Sandbox {
+    &self.sandbox
+  }
+
+  /// Whether scripts cannot define `name`, because the sandbox
+  /// protects it or the builtin registered under it.
+  pub fn is_protected(&self, name: &str) -> bool {
+    self.sandbox.protected.contains(name)
+      || self.sandbox.protect_builtins
+        && self.builtin_groups.contains_key(name)
+  }
+
+  /// Lists the native operators available to scripts, sorted by
+  /// name.
+  pub fn operators(&self) -> Vec<OperatorInfo> {
+    let mut ret: Vec<_> = self
       .globals
-      .insert(name, Value::Native(NativeOp(Rc::new(f))));
+      .iter()
+      .filter(|(_, value)| matches!(value, Value::Native(_)))
+      .map(|(name, _)| OperatorInfo {
+        name: name.clone(),
+        group: self.builtin_groups.get(name).copied(),
+        protected: self.is_protected(name),
+      })
+      .collect();
+    ret.sort_by(|a, b| a.name.cmp(&b.name));
+    ret
+  }
+
+  pub fn add_fn(&mut self, name: String, f: Box<HostFn>) {
+    self.globals.insert(
+      name,
+      Value::Native(NativeOp(Shared::new(Box::new(
+        move |vm| {
+          f(vm);
+          Ok(())
+        },
+      )))),
+    );
   }
 
-  fn find_var(&self, name: &str) -> Option<Value<'f>> {
+  fn find_var(&self, name: &str) -> Option<Value> {
     self
       .exec_stack
       .iter()
       .rev()
       .find_map(|state| {
-        state.as_frame().vars.get(name).cloned()
+        let frame = state.as_frame();
+        frame.vars.get(name).cloned().or_else(|| {
+          frame.module.as_ref()?.vars.get(name).cloned()
+        })
       })
+      .or_else(|| self.root_vars.get(name).cloned())
       .or_else(|| self.globals.get(name).cloned())
+      .or_else(|| {
+        let (prefix, name) = name.split_once('.')?;
+        let Value::Module(path) = self.find_var(prefix)? else {
+          return None;
+        };
+        let module = self.modules.get(&path)?;
+        if !module.exports.iter().any(|export| export == name) {
+          return None;
+        }
+        module.vars.get(name).cloned()
+      })
+  }
+
+  /// Returns the module that defines the code at the offset.
+  fn module_at(&self, offset: usize) -> Option<Shared<Module>> {
+    let file = self.source_map.file(offset)?;
+    self.modules.get(&file.name).cloned()
+  }
+
+  /// Creates a frame to run a block, remembering the module which
+  /// defines it.
+  fn new_frame(
+    &self,
+    name: String,
+    block: BlockSpan,
+  ) -> ExecFrame {
+    let module = self.module_at(block.span.0);
+    let mut frame = ExecFrame::new(name, block);
+    frame.module = module;
+    frame
+  }
+
+  pub fn get_module(&self, path: &str) -> Option<&Module> {
+    self.modules.get(path).map(|module| &**module)
+  }
+
+  /// Variables defined at the top level of the sources.
+  ///
+  /// They are distinct from the globals, which are set by the host
+  /// and shadowed by the root-level definitions of the same names.
+  pub fn root_vars(&self) -> &HashMap<String, Value> {
+    &self.root_vars
+  }
+
+  /// Returns a global set by the host, a builtin or the prelude.
+  /// Root-level definitions of scripts are in
+  /// [`Vm::root_vars`].
+  pub fn get_global(&self, name: &str) -> Option<&Value> {
+    self.globals.get(name)
+  }
+
+  /// Sets a global, which scripts can read like a variable. It
+  /// replaces a builtin of the same name and is not subject to the
+  /// protection of [`Sandbox::protected`]. Returns the previous
+  /// value.
+  pub fn set_global(
+    &mut self,
+    name: &str,
+    value: Value,
+  ) -> Option<Value> {
+    self.builtin_groups.remove(name);
+    let new =
+      self.debugger.is_watched(name).then(|| value.clone());
+    let old = self.globals.insert(name.to_string(), value);
+    if let Some(new) = new {
+      self.debugger.hit_watchpoints(
+        name,
+        old.clone(),
+        new,
+        None,
+      );
+    }
+    old
+  }
+
+  pub fn remove_global(&mut self, name: &str) -> Option<Value> {
+    self.builtin_groups.remove(name);
+    self.globals.remove(name)
+  }
+
+  /// Iterates over the globals including the builtins, in no
+  /// particular order.
+  pub fn globals(
+    &self,
+  ) -> impl Iterator<Item = (&str, &Value)> + '_ {
+    self
+      .globals
+      .iter()
+      .map(|(name, value)| (name.as_str(), value))
+  }
+
+  /// Looks up a name like scripts do in the current state: from
+  /// the innermost frame through the root-level definitions to the
+  /// globals.
+  pub fn lookup(&self, name: &str) -> Option<Value> {
+    self.find_var(name)
+  }
+
+  /// Returns the stack effect declared for a function, if any.
+  pub fn signature(&self, name: &str) -> Option<StackEffect> {
+    match self.find_var(name)? {
+      Value::Block(block) => block.effect,
+      _ => None,
+    }
   }
 
+  /// Returns the local variables of the innermost frame, or the
+  /// root-level definitions if it is a root frame or nothing is
+  /// running.
   pub fn get_vars(&self) -> &HashMap<String, Value> {
-    &self.exec_stack.last().unwrap().as_frame().vars
+    match self.exec_stack.last().map(|state| state.as_frame()) {
+      Some(frame) if !frame.is_root() => &frame.vars,
+      _ => &self.root_vars,
+    }
+  }
+
+  pub fn parse_batch(
+    &mut self,
+    source: impl BufRead,
+  ) -> Result<(), ParseError> {
+    self.parse_source("<input>", source)
+  }
+
+  /// Parses the source and registers it to the source map with the
+  /// given file name, which will be shown in error messages.
+  ///
+  /// Each source is compiled into its own unit, which runs after
+  /// the code already in the execution stack. The operand stack and
+  /// the variables defined by previous units are kept, so a `Vm`
+  /// can run multiple sources one by one like a REPL.
+  ///
+  /// If the source has a syntax error, nothing is added to the code
+  /// to be executed, so the `Vm` can be reused.
+  pub fn parse_source(
+    &mut self,
+    name: &str,
+    source: impl BufRead,
+  ) -> Result<(), ParseError> {
+    if let Some(frame) = self.compile_unit(name, source)? {
+      self.exec_stack.insert(0, ExecState::Frame(frame));
+    }
+    Ok(())
+  }
+
+  /// Parses and runs the source to completion. If it fails, the
+  /// rest of the execution is discarded, but the definitions and
+  /// the operand stack are kept.
+  ///
+  /// The source runs on top of any paused execution, like the rest
+  /// of a [`Vm::parse_source`]d unit, which is left as it was and
+  /// can be resumed afterwards. Names are looked up from the paused
+  /// frames too, like in a debugger.
+  pub fn eval_source(
+    &mut self,
+    name: &str,
+    source: impl BufRead,
+  ) -> Result<(), Error> {
+    let Some(frame) = self.compile_unit(name, source)? else {
+      return Ok(());
+    };
+    let depth = self.exec_stack.len();
+    self.exec_stack.push(ExecState::Frame(frame));
+    while depth < self.exec_stack.len() {
+      if let Err(e) = self.step() {
+        self.exec_stack.truncate(depth);
+        return Err(e.into());
+      }
+    }
+    Ok(())
+  }
+
+  /// Parses the source into the root frame of a new unit.
+  fn compile_unit(
+    &mut self,
+    name: &str,
+    mut source: impl BufRead,
+  ) -> Result<Option<ExecFrame>, ParseError> {
+    let mut text = String::new();
+    source
+      .read_to_string(&mut text)
+      .map_err(|e| ParseError::read(&e))?;
+    let end = text.len();
+    let start =
+      self.parse_text(name, text).inspect_err(|_| {
+        self.blocks.clear();
+      })?;
+
+    Ok(self.blocks.pop().map(|mut unit| {
+      unit.span.1 = start + end;
+      let mut frame = ExecFrame::new("root".to_owned(), unit);
+      frame.root = true;
+      frame
+    }))
   }
 
-  pub fn parse_batch(&mut self, source: impl BufRead) {
+  /// Parses the text into a new block in `self.blocks` and returns
+  /// the offset of the text.
+  fn parse_text(
+    &mut self,
+    name: &str,
+    text: String,
+  ) -> Result<usize, ParseError> {
     let mut tokenbuf = vec![];
-    let mut byte_count = 0;
-    for byte in source.bytes().map(|b| b.unwrap()) {
+    let start =
+      self.source_map.add_file(name.to_string(), text.clone());
+    let mut byte_count = start;
+    self.blocks = vec![BlockSpan::new(start)];
+    // Nesting depth of parentheses while reading a string literal
+    let mut string_depth = 0;
+    let mut string_start = 0;
+    for byte in text.bytes() {
+      if 0 < string_depth {
+        match byte {
+          b'(' => string_depth += 1,
+          b')' => string_depth -= 1,
+          _ => (),
+        }
+        if string_depth == 0 {
+          let s =
+            String::from_utf8_lossy(&tokenbuf).to_string();
+          push_value(
+            self,
+            Value::Str(s),
+            (string_start, byte_count + 1),
+          );
+          tokenbuf.clear();
+        } else {
+          tokenbuf.push(byte);
+        }
+        byte_count += 1;
+        continue;
+      }
       match byte {
         b' ' | b'\t' | b'\r' | b'\n' => {
           parse_word(
             std::str::from_utf8(&tokenbuf).unwrap(),
             self,
             byte_count - tokenbuf.len(),
-          );
+          )?;
           tokenbuf.clear();
         }
+        b'(' if tokenbuf.is_empty() => {
+          string_depth = 1;
+          string_start = byte_count;
+        }
         _ => tokenbuf.push(byte),
       }
       byte_count += 1;
     }
 
+    if 0 < string_depth {
+      return Err(ParseError::new(
+        ParseErrorKind::UnclosedString,
+        (string_start, string_start + 1),
+        &self.source_map,
+      ));
+    }
+
     parse_word(
       std::str::from_utf8(&tokenbuf).unwrap(),
       self,
       byte_count - tokenbuf.len(),
-    );
+    )?;
+
+    if let Some(unclosed) = self.blocks.get(1) {
+      let start = unclosed.span.0;
+      return Err(ParseError::new(
+        ParseErrorKind::UnclosedBlock,
+        (start, start + 1),
+        &self.source_map,
+      ));
+    }
+    Ok(start)
+  }
 
-    if let Some(top_block) = self.blocks.first() {
-      self.exec_stack.push(ExecState::Frame(ExecFrame::new(
-        "root".to_owned(),
-        top_block.clone(),
-      )));
+  /// Calls a function or an operator with the given arguments and
+  /// returns the values it leaves on the stack.
+  ///
+  /// The function runs to completion in a new frame on top of the
+  /// current state, so it can be called from native functions too.
+  /// On error, the frames it entered are discarded. It is an error
+  /// for the function to consume more values than its arguments.
+  pub fn call(
+    &mut self,
+    name: &str,
+    args: &[Value],
+  ) -> Result<Vec<Value>, EvalError> {
+    let base = self.stack.len();
+    let depth = self.exec_stack.len();
+    self.stack.extend_from_slice(args);
+    let mut res = eval(&Value::Op(name.to_string()), self)
+      .map_err(|e| {
+        EvalError::new(e, None, &self.source_map, self.trace())
+      });
+    while res.is_ok() && depth < self.exec_stack.len() {
+      res = self.step().map(|_| ());
     }
+    if let Err(e) = res {
+      self.exec_stack.truncate(depth);
+      self.stack.truncate(base);
+      return Err(e);
+    }
+    if self.stack.len() < base {
+      return Err(EvalError::new(
+        format!("{name:?} consumed below its arguments"),
+        None,
+        &self.source_map,
+        self.trace(),
+      ));
+    }
+    Ok(self.stack.split_off(base))
   }
 
-  pub fn eval_all(&mut self) -> Result<(), String> {
-    while self.eval_step().map(|r| r.is_some())? {}
+  pub fn eval_all(&mut self) -> Result<(), EvalError> {
+    while self.step().map(|r| r.is_some())? {}
     Ok(())
   }
 
-  fn map_err(&self, e: String) -> String {
-    format!("{e}:\n{}", self.stack_trace())
+  fn map_err(
+    &self,
+    e: String,
+    span: (usize, usize),
+  ) -> EvalError {
+    EvalError::new(
+      e,
+      Some(span),
+      &self.source_map,
+      self.trace(),
+    )
+  }
+
+  /// Returns the call stack from the innermost frame.
+  fn trace(&self) -> Vec<TraceFrame> {
+    self
+      .exec_stack
+      .iter()
+      .enumerate()
+      .rev()
+      .map(|(i, state)| {
+        let call_site = i
+          .checked_sub(1)
+          .map(|parent| self.exec_stack[parent].as_frame())
+          .and_then(|parent| {
+            self.source_map.lookup(parent.current_span().0)
+          });
+        TraceFrame {
+          name: state.as_frame().name.clone(),
+          call_site,
+        }
+      })
+      .collect()
   }
 
+  /// Runs a single token and returns its span, or `None` if
+  /// nothing is left to run. The definitions of watched variables
+  /// in the step are returned by [`Vm::take_watch_hits`] until the
+  /// next one.
   pub fn eval_step(
     &mut self,
-  ) -> Result<Option<(usize, usize)>, String> {
-    let get_step = |frame: &mut ExecFrame<'f>| {
+  ) -> Result<Option<(usize, usize)>, EvalError> {
+    self.debugger.paused = false;
+    self.debugger.watch_hits.clear();
+    self.debugger.watching = true;
+    let res = self.step();
+    self.debugger.watching = false;
+    res
+  }
+
+  /// Runs a single token like [`Vm::eval_step`], without watching
+  /// variables for a debugger.
+  fn step(
+    &mut self,
+  ) -> Result<Option<(usize, usize)>, EvalError> {
+    let get_step = |frame: &mut ExecFrame| {
       if frame.ip < frame.block.block.len() {
         let value_span = frame.block.block[frame.ip].clone();
         frame.ip += 1;
@@ -301,28 +1212,76 @@ impl<'f> Vm<'f> {
       Ok(match state {
         ExecState::Frame(frame)
         | ExecState::IfTrue(frame)
-        | ExecState::IfFalse(frame) => {
+        | ExecState::IfFalse(frame)
+        | ExecState::Include(frame)
+        | ExecState::Module { frame, .. } => {
           if let Some(value_span) = get_step(frame) {
             eval(&value_span.value, self)
-              .map_err(|e| self.map_err(e))?;
+              .map_err(|e| self.map_err(e, value_span.span))?;
             Some(value_span.span)
           } else {
-            let frame = self.exec_stack.pop();
-            Some(
-              frame
-                .map(|frame| frame.as_frame().block.span)
-                .unwrap_or((0, 0)),
-            )
+            if let (Some(base), Some(effect)) =
+              (frame.stack_base, &frame.block.effect)
+            {
+              // Point to the closing brace of the block
+              let end = frame.block.span.1;
+              check_returns(
+                &frame.name,
+                effect,
+                base,
+                &self.stack,
+              )
+              .map_err(|e| {
+                self.map_err(e, (end.saturating_sub(1), end))
+              })?;
+            }
+            let state = self.exec_stack.pop();
+            let span = state
+              .as_ref()
+              .map(|state| state.as_frame().block.span)
+              .unwrap_or((0, 0));
+            if let Some(ExecState::Module {
+              frame,
+              import,
+              exports,
+            }) = state
+            {
+              let path = frame.name;
+              self.modules.insert(
+                path.clone(),
+                Shared::new(Module {
+                  vars: frame.vars,
+                  exports,
+                }),
+              );
+              bind_module(self, &path, import)
+                .map_err(|e| self.map_err(e, span))?;
+            }
+            Some(span)
           }
         }
         ExecState::IfCond { frame, .. } => {
           if let Some(value_span) = get_step(frame) {
             eval(&value_span.value, self)
-              .map_err(|e| self.map_err(e))?;
+              .map_err(|e| self.map_err(e, value_span.span))?;
             Some(value_span.span)
           } else {
-            let cond = self.stack.pop().unwrap();
-            if cond.as_int() != 0 {
+            let cond = self
+              .try_pop()
+              .and_then(|cond| cond.try_bool())
+              .map_err(|e| {
+                self.map_err(
+                  e,
+                  self
+                    .exec_stack
+                    .last()
+                    .unwrap()
+                    .as_frame()
+                    .block
+                    .span,
+                )
+              })?;
+            if cond {
               let block = if let ExecState::IfCond {
                 true_branch,
                 ..
@@ -337,9 +1296,9 @@ impl<'f> Vm<'f> {
                 .first()
                 .map(|first| first.span)
                 .unwrap_or((0, 0));
-              self.exec_stack.push(ExecState::IfTrue(
-                ExecFrame::new("<IfTrue>".to_owned(), block),
-              ));
+              let frame =
+                self.new_frame("<IfTrue>".to_owned(), block);
+              self.exec_stack.push(ExecState::IfTrue(frame));
               Some(ret)
             } else {
               let block = if let ExecState::IfCond {
@@ -356,9 +1315,9 @@ impl<'f> Vm<'f> {
                 .first()
                 .map(|first| first.span)
                 .unwrap_or((0, 0));
-              self.exec_stack.push(ExecState::IfFalse(
-                ExecFrame::new("<IfFalse>".to_owned(), block),
-              ));
+              let frame =
+                self.new_frame("<IfFalse>".to_owned(), block);
+              self.exec_stack.push(ExecState::IfFalse(frame));
               Some(ret)
             }
           }
@@ -369,7 +1328,7 @@ impl<'f> Vm<'f> {
           }
           if let Some(value_span) = get_step(frame) {
             eval(&value_span.value, self)
-              .map_err(|e| self.map_err(e))?;
+              .map_err(|e| self.map_err(e, value_span.span))?;
             break Some(value_span.span);
           } else {
             *i += 1;
@@ -390,41 +1349,6 @@ impl<'f> Vm<'f> {
       Ok(None)
     }
   }
-
-  fn stack_trace(&self) -> String {
-    self
-      .exec_stack
-      .iter()
-      .rev()
-      .enumerate()
-      .map(|(i, state)| match state {
-        ExecState::Frame(frame)
-        | ExecState::IfTrue(frame)
-        | ExecState::IfFalse(frame)
-        | ExecState::IfCond { frame, .. }
-        | ExecState::For { frame, .. } => {
-          let local_vars = frame.vars.iter().map(|(k, v)| format!("{k}: {}", v.to_string())).fold("".to_string(), |acc, cur| {
-            if acc.is_empty() {
-              cur
-            } else {
-              acc + ", " + &cur
-            }
-          });
-          let stack_vars = frame
-            .block
-            .block
-            .iter()
-            .map(|value| value.value.to_string())
-            .fold("".to_string(), |acc, cur| {
-              acc + " " + &cur
-            });
-          format!("    frame[{i}]: locals: {{{local_vars}}}, stack: {stack_vars}")
-        }
-      })
-      .fold("  Stack trace:\n".to_string(), |acc, cur| {
-        acc + &cur + "\n"
-      })
-  }
 }
 
 pub fn parse_interactive() {
@@ -433,29 +1357,41 @@ pub fn parse_interactive() {
     for word in line.split(" ") {
       let offset =
         word.as_ptr() as usize - line.as_ptr() as usize;
-      parse_word(word, &mut vm, offset);
+      if let Err(e) = parse_word(word, &mut vm, offset) {
+        println!("{e}");
+      }
     }
     println!("stack: {:?}", vm.stack);
   }
 }
 
-fn parse_word(word: &str, vm: &mut Vm, offset: usize) {
+fn parse_word(
+  word: &str,
+  vm: &mut Vm,
+  offset: usize,
+) -> Result<(), ParseError> {
   if word.is_empty() {
-    return;
+    return Ok(());
   }
   if word == "{" {
     vm.blocks.push(BlockSpan::new(offset));
   } else if word == "}" {
-    let mut new_block =
-      vm.blocks.pop().expect("Block stack underflow!");
+    if vm.blocks.len() <= 1 {
+      return Err(ParseError::new(
+        ParseErrorKind::UnmatchedClose,
+        (offset, offset + 1),
+        &vm.source_map,
+      ));
+    }
+    let mut new_block = vm.blocks.pop().unwrap();
     if let Some(top_block) = vm.blocks.last_mut() {
       new_block.span.1 = offset + 1;
-      top_block.block.push(ValueSpan {
+      Shared::make_mut(&mut top_block.block).push(ValueSpan {
         span: (new_block.span.0, offset + 1),
         value: Value::Block(new_block),
       });
     }
-  } else if let Some(top_block) = vm.blocks.last_mut() {
+  } else {
     let code = if let Ok(num) = word.parse::<i32>() {
       Value::Int(num)
     } else if let Ok(num) = word.parse::<f32>() {
@@ -465,30 +1401,44 @@ fn parse_word(word: &str, vm: &mut Vm, offset: usize) {
     } else {
       Value::Op(word.to_string())
     };
-    top_block.block.push(ValueSpan {
-      value: code,
-      span: (offset, offset + word.len()),
-    });
-    // eval(code, vm);
+    push_value(vm, code, (offset, offset + word.len()));
   }
+  Ok(())
 }
 
-fn eval<'f>(
-  code: &Value<'f>,
-  vm: &mut Vm<'f>,
-) -> Result<(), String> {
+fn push_value(vm: &mut Vm, value: Value, span: (usize, usize)) {
+  if let Some(top_block) = vm.blocks.last_mut() {
+    Shared::make_mut(&mut top_block.block)
+      .push(ValueSpan { value, span });
+  }
+}
+
+fn eval(code: &Value, vm: &mut Vm) -> Result<(), String> {
   if let Value::Op(ref op) = code {
     let val = vm.find_var(op).ok_or_else(|| {
       format!("{op:?} is not a defined operation")
     })?;
     match val {
       Value::Block(block) => {
-        vm.exec_stack.push(ExecState::Frame(ExecFrame::new(
-          op.clone(),
-          block,
-        )));
+        let stack_base = match (&block.effect, vm.debug) {
+          (Some(effect), true) => {
+            let args = effect.inputs.len();
+            if vm.stack.len() < args {
+              return Err(format!(
+                "{op:?} {effect} expects {args} argument(s), \
+                but the stack has {}",
+                vm.stack.len()
+              ));
+            }
+            Some(vm.stack.len() - args)
+          }
+          _ => None,
+        };
+        let mut frame = vm.new_frame(op.clone(), block);
+        frame.stack_base = stack_base;
+        vm.exec_stack.push(ExecState::Frame(frame));
       }
-      Value::Native(op) => op.0(vm),
+      Value::Native(op) => op.0(vm)?,
       _ => vm.stack.push(val),
     }
   } else {
@@ -497,18 +1447,41 @@ fn eval<'f>(
   Ok(())
 }
 
+fn check_returns(
+  name: &str,
+  effect: &StackEffect,
+  stack_base: usize,
+  stack: &[Value],
+) -> Result<(), String> {
+  let expected = stack_base + effect.outputs.len();
+  if stack.len() != expected {
+    return Err(format!(
+      "{name:?} {effect} should return {} value(s), \
+      but returned {}",
+      effect.outputs.len(),
+      stack.len() as isize - stack_base as isize
+    ));
+  }
+  Ok(())
+}
+
 macro_rules! impl_op {
     {$name:ident, $op:tt} => {
-        fn $name(vm: &mut Vm) {
-            let rhs = vm.stack.pop().unwrap();
-            let lhs = vm.stack.pop().unwrap();
+        fn $name(vm: &mut Vm) -> Result<(), String> {
+            let rhs = vm.try_pop()?;
+            let lhs = vm.try_pop()?;
             vm.stack.push(match (lhs, rhs) {
                 (Value::Int(lhs), Value::Int(rhs)) => Value::Int((lhs $op rhs) as i32),
                 (Value::Num(lhs), Value::Int(rhs)) => Value::Num(lhs as f32 $op rhs as f32),
                 (Value::Int(lhs), Value::Num(rhs)) => Value::Num(lhs as f32 $op rhs as f32),
                 (Value::Num(lhs), Value::Num(rhs)) => Value::Num(lhs $op rhs),
-                _ => panic!("Binary arithmetic between incompatible types!"),
+                (lhs, rhs) => return Err(format!(
+                    "Binary arithmetic between incompatible types {} and {}",
+                    lhs.type_name(),
+                    rhs.type_name()
+                )),
             });
+            Ok(())
         }
     }
 }
@@ -518,121 +1491,362 @@ impl_op!(sub, -);
 impl_op!(mul, *);
 impl_op!(div, /);
 
-fn lt(vm: &mut Vm) {
-  let rhs = vm.stack.pop().unwrap().as_num();
-  let lhs = vm.stack.pop().unwrap().as_num();
+fn lt(vm: &mut Vm) -> Result<(), String> {
+  let rhs = vm.try_pop()?.try_num()?;
+  let lhs = vm.try_pop()?.try_num()?;
   vm.stack.push(Value::Int((lhs < rhs) as i32));
+  Ok(())
 }
 
-fn op_or(vm: &mut Vm){
-  let rhs = vm.stack.pop().unwrap().as_bool();
-  let lhs = vm.stack.pop().unwrap().as_bool();
+fn op_or(vm: &mut Vm) -> Result<(), String> {
+  let rhs = vm.try_pop()?.try_bool()?;
+  let lhs = vm.try_pop()?.try_bool()?;
   vm.stack.push(Value::Int((lhs || rhs) as i32));
+  Ok(())
 }
 
-fn op_and(vm: &mut Vm){
-  let rhs = vm.stack.pop().unwrap().as_bool();
-  let lhs = vm.stack.pop().unwrap().as_bool();
+fn op_and(vm: &mut Vm) -> Result<(), String> {
+  let rhs = vm.try_pop()?.try_bool()?;
+  let lhs = vm.try_pop()?.try_bool()?;
   vm.stack.push(Value::Int((lhs && rhs) as i32));
+  Ok(())
 }
 
-fn sin(vm: &mut Vm) {
-  let o = vm.pop().unwrap().as_num();
+fn sin(vm: &mut Vm) -> Result<(), String> {
+  let o = vm.try_pop()?.try_num()?;
   vm.stack.push(Value::Num(o.sin()));
+  Ok(())
 }
 
-fn cos(vm: &mut Vm) {
-  let o = vm.pop().unwrap().as_num();
+fn cos(vm: &mut Vm) -> Result<(), String> {
+  let o = vm.try_pop()?.try_num()?;
   vm.stack.push(Value::Num(o.cos()));
+  Ok(())
 }
 
-fn op_if(vm: &mut Vm) {
-  let false_branch = vm.stack.pop().unwrap().to_block();
-  let true_branch = vm.stack.pop().unwrap().to_block();
-  let cond = vm.stack.pop().unwrap().to_block();
+fn pi(vm: &mut Vm) -> Result<(), String> {
+  vm.stack.push(Value::Num(std::f32::consts::PI));
+  Ok(())
+}
 
+fn op_if(vm: &mut Vm) -> Result<(), String> {
+  let false_branch = vm.try_pop()?.try_block()?;
+  let true_branch = vm.try_pop()?.try_block()?;
+  let cond = vm.try_pop()?.try_block()?;
+
+  let frame = vm.new_frame("<IfCond>".to_owned(), cond);
   vm.exec_stack.push(ExecState::IfCond {
-    frame: ExecFrame::new("<IfCond>".to_owned(), cond),
+    frame,
     true_branch,
     false_branch,
   });
+  Ok(())
 }
 
-fn op_for(vm: &mut Vm) {
-  let f = vm.stack.pop().unwrap().to_block();
-  let end = vm.stack.pop().unwrap().as_int();
-  let start = vm.stack.pop().unwrap().as_int();
+fn op_for(vm: &mut Vm) -> Result<(), String> {
+  let f = vm.try_pop()?.try_block()?;
+  let end = vm.try_pop()?.try_int()?;
+  let start = vm.try_pop()?.try_int()?;
 
+  let frame = vm.new_frame("<For>".to_owned(), f);
   vm.exec_stack.push(ExecState::For {
-    frame: ExecFrame::new("<For>".to_owned(), f),
+    frame,
     i: start,
     end,
   });
+  Ok(())
 }
 
-fn op_def(vm: &mut Vm) {
-  let value = vm.stack.pop().unwrap();
+fn op_def(vm: &mut Vm) -> Result<(), String> {
+  let value = vm.try_pop()?;
   if let Err(e) = eval(&value, vm) {
-    println!("eval returned error: {e:?}");
+    writeln!(vm.error_output, "eval returned error: {e:?}")
+      .map_err(|e| format!("Failed to write output: {e}"))?;
   }
-  let value = vm.stack.pop().unwrap();
-  let sym = vm.stack.pop().unwrap().as_sym().to_string();
+  let value = vm.try_pop()?;
+  let sym = vm.try_pop()?.try_sym()?.to_string();
+  define(vm, sym, value)
+}
 
-  vm.exec_stack
+fn op_defn(vm: &mut Vm) -> Result<(), String> {
+  let effect = vm.try_pop()?;
+  let mut block = vm.try_pop()?.try_block()?;
+  let sym = vm.try_pop()?.try_sym()?.to_string();
+  let Value::Str(effect) = effect else {
+    return Err(format!(
+      "defn expects a stack effect string, got {}",
+      effect.type_name()
+    ));
+  };
+  block.effect = Some(StackEffect::parse(&effect)?);
+  define(vm, sym, Value::Block(block))
+}
+
+fn define(
+  vm: &mut Vm,
+  sym: String,
+  value: Value,
+) -> Result<(), String> {
+  if vm.is_protected(&sym) {
+    return Err(format!("{sym:?} is protected"));
+  }
+  let watched = vm
+    .debugger
+    .is_watched(&sym)
+    .then(|| (sym.clone(), value.clone()));
+  let span = vm
+    .exec_stack
+    .last()
+    .map(|state| state.as_frame().current_span());
+  let frame = vm
+    .exec_stack
     .iter_mut()
     .rev()
-    .find(|frame| matches!(frame, ExecState::Frame(_)))
-    .unwrap()
-    .as_frame_mut()
-    .vars
-    .insert(sym, value);
+    .find(|frame| {
+      matches!(
+        frame,
+        ExecState::Frame(_) | ExecState::Module { .. }
+      )
+    })
+    .map(|frame| frame.as_frame_mut());
+  let old = match frame {
+    Some(frame) if !frame.root => frame.vars.insert(sym, value),
+    _ => vm.root_vars.insert(sym, value),
+  };
+  if let Some((name, new)) = watched {
+    vm.debugger.hit_watchpoints(&name, old, new, span);
+  }
+  Ok(())
+}
+
+fn load(vm: &mut Vm) -> Result<(), String> {
+  let key = vm.try_pop()?;
+  let name = key.try_sym()?;
+  let value = vm
+    .find_var(name)
+    .ok_or_else(|| format!("{name:?} is not defined"))?;
+  vm.stack.push(value);
+  Ok(())
 }
 
-fn puts(vm: &mut Vm) {
-  let value = vm.stack.pop().unwrap();
-  println!("{}", value.to_string());
+fn include(vm: &mut Vm) -> Result<(), String> {
+  load_file(vm, true)
 }
 
-fn pop(vm: &mut Vm) {
-  vm.stack.pop().unwrap();
+fn run(vm: &mut Vm) -> Result<(), String> {
+  load_file(vm, false)
+}
+
+/// Loads the file at the path on the stack and executes it. If
+/// `once` is true, a file that is already loaded is skipped.
+fn load_file(vm: &mut Vm, once: bool) -> Result<(), String> {
+  let Value::Str(path) = vm.try_pop()? else {
+    return Err("Expected a file path string".to_string());
+  };
+  let path = resolve_file(vm, &path)?;
+  if once && vm.loaded_files.contains_key(&path) {
+    return Ok(());
+  }
+  let block = compile_file(vm, &path)?;
+  vm.exec_stack
+    .push(ExecState::Include(ExecFrame::new(path, block)));
+  Ok(())
 }
 
-fn dup(vm: &mut Vm) {
-  let value = vm.stack.last().unwrap();
-  vm.stack.push(value.clone());
+/// Resolves the path relative to the file being executed, and
+/// checks that the file is not being executed already.
+fn resolve_file(vm: &Vm, path: &str) -> Result<String, String> {
+  let loader = vm
+    .file_loader
+    .as_ref()
+    .ok_or_else(|| "File loader is not set".to_string())?;
+  let base = vm
+    .exec_stack
+    .last()
+    .and_then(|state| {
+      vm.source_map.file(state.as_frame().current_span().0)
+    })
+    .map(|file| file.name.as_str());
+  let path = loader.resolve(base, path);
+
+  // The files being executed, from the outermost
+  let loading: Vec<_> = vm
+    .exec_stack
+    .iter()
+    .filter_map(|state| match state {
+      ExecState::Include(frame)
+      | ExecState::Module { frame, .. } => {
+        Some(frame.name.clone())
+      }
+      ExecState::Frame(frame) if frame.root => {
+        let file = vm.source_map.file(frame.block.span.0)?;
+        Some(loader.resolve(None, &file.name))
+      }
+      _ => None,
+    })
+    .collect();
+  if loading.contains(&path) {
+    return Err(format!(
+      "Include cycle detected: {} -> {path}",
+      loading.join(" -> ")
+    ));
+  }
+  Ok(path)
 }
 
-fn exch(vm: &mut Vm) {
-  let last = vm.stack.pop().unwrap();
-  let second = vm.stack.pop().unwrap();
-  vm.stack.push(last);
-  vm.stack.push(second);
+/// Returns the compiled code of the file, loading it if necessary.
+fn compile_file(
+  vm: &mut Vm,
+  path: &str,
+) -> Result<BlockSpan, String> {
+  if let Some(block) = vm.loaded_files.get(path) {
+    return Ok(block.clone());
+  }
+  let loader = vm
+    .file_loader
+    .as_ref()
+    .ok_or_else(|| "File loader is not set".to_string())?;
+  let text = loader.load(path)?;
+  let end = text.len();
+  let start = vm.parse_text(path, text).map_err(|e| {
+    vm.blocks.clear();
+    e.to_string()
+  })?;
+  let mut block = vm.blocks.pop().unwrap();
+  block.span.1 = start + end;
+  vm.loaded_files.insert(path.to_string(), block.clone());
+  Ok(block)
 }
 
-fn index(vm: &mut Vm) {
-  let index = vm.stack.pop().unwrap().as_num() as usize;
-  let value = vm.stack[vm.stack.len() - index - 1].clone();
-  vm.stack.push(value);
+fn import(vm: &mut Vm) -> Result<(), String> {
+  let Value::Str(name) = vm.try_pop()? else {
+    return Err("Expected a module name string".to_string());
+  };
+  // The prefix is the file name without directories and extension
+  let prefix = name.rsplit('/').next().unwrap_or(&name);
+  let prefix = prefix.split('.').next().unwrap_or(prefix);
+  let import = Import::Prefix(prefix.to_string());
+  load_module(vm, &name, import)
 }
 
-fn load(vm: &mut Vm) {
-  let key = vm.stack.pop().unwrap();
-  let value = vm.find_var(key.as_sym()).unwrap();
-  vm.stack.push(value);
+fn importfrom(vm: &mut Vm) -> Result<(), String> {
+  let (Value::Str(names), Value::Str(name)) =
+    (vm.try_pop()?, vm.try_pop()?)
+  else {
+    return Err("Expected module and name strings".to_string());
+  };
+  let names = names.split_whitespace().map(|s| s.to_string());
+  load_module(vm, &name, Import::Names(names.collect()))
+}
+
+/// Loads the module if it is not loaded yet, and binds its exports
+/// to the current scope after loading.
+fn load_module(
+  vm: &mut Vm,
+  name: &str,
+  import: Import,
+) -> Result<(), String> {
+  // Module names may omit the default extension
+  let file_name = name.rsplit('/').next().unwrap_or(name);
+  let path = if file_name.contains('.') {
+    resolve_file(vm, name)?
+  } else {
+    resolve_file(vm, &format!("{name}.txt"))?
+  };
+  if vm.modules.contains_key(&path) {
+    return bind_module(vm, &path, import);
+  }
+  let block = compile_file(vm, &path)?;
+  vm.exec_stack.push(ExecState::Module {
+    frame: ExecFrame::new(path, block),
+    import,
+    exports: vec![],
+  });
+  Ok(())
+}
+
+fn bind_module(
+  vm: &mut Vm,
+  path: &str,
+  import: Import,
+) -> Result<(), String> {
+  match import {
+    Import::Prefix(prefix) => {
+      define(vm, prefix, Value::Module(path.to_string()))?;
+    }
+    Import::Names(names) => {
+      for name in names {
+        let module = &vm.modules[path];
+        let value = module
+          .exports
+          .contains(&name)
+          .then(|| module.vars.get(&name).cloned())
+          .flatten()
+          .ok_or_else(|| {
+            format!("Module {path:?} does not export {name:?}")
+          })?;
+        define(vm, name, value)?;
+      }
+    }
+  }
+  Ok(())
+}
+
+fn export(vm: &mut Vm) -> Result<(), String> {
+  let name = vm.try_pop()?.try_sym()?.to_string();
+  let exports = vm
+    .exec_stack
+    .iter_mut()
+    .rev()
+    .find_map(|state| match state {
+      ExecState::Module { exports, .. } => Some(exports),
+      _ => None,
+    })
+    .ok_or_else(|| {
+      "export is used outside of a module".to_string()
+    })?;
+  exports.push(name);
+  Ok(())
 }
 
 #[cfg(test)]
 mod test {
   use super::{Value::*, *};
-  use std::io::Cursor;
+
+  /// Runs a source in a new `Vm` and returns the values it leaves,
+  /// for the tests of the operators.
+  pub(crate) fn run(input: &str) -> Result<Vec<Value>, String> {
+    let mut vm = Vm::new();
+    vm.parse_batch(Cursor::new(input)).unwrap();
+    vm.eval_all().map_err(|e| e.to_string())?;
+    Ok(vm.get_stack().to_vec())
+  }
+
+  /// Runs a source named `a` in the `Vm` and takes the values it
+  /// leaves.
+  pub(crate) fn run_in(
+    vm: &mut Vm,
+    src: &str,
+  ) -> Result<Vec<Value>, String> {
+    vm.eval_source("a", Cursor::new(src))
+      .map_err(|e| e.to_string())?;
+    Ok(std::mem::take(&mut vm.stack))
+  }
 
   fn parse(input: &str) -> Vec<Value> {
     let mut vm = Vm::new();
-    vm.parse_batch(Cursor::new(input));
+    vm.parse_batch(Cursor::new(input)).unwrap();
     vm.eval_all();
     vm.get_stack().to_vec()
   }
 
+  fn parse_err(input: &str) -> String {
+    let mut vm = Vm::new();
+    vm.parse_batch(Cursor::new(input)).unwrap();
+    vm.set_debug(true);
+    vm.eval_all().unwrap_err().to_string()
+  }
+
   fn span(value: Value, span: (usize, usize)) -> ValueSpan {
     ValueSpan { value, span }
   }
@@ -644,11 +1858,12 @@ mod test {
       vec![
         Int(3),
         Block(BlockSpan {
-          block: vec![
+          block: Shared::new(vec![
             span(Int(3), (8, 9)),
             span(Int(4), (10, 11))
-          ],
+          ]),
           span: (6, 13),
+          effect: None,
         })
       ]
     );
@@ -715,4 +1930,416 @@ if
       vec![Int(20)]
     );
   }
+
+  #[test]
+  fn test_string() {
+    assert_eq!(
+      parse("(hello (nested) world) 1"),
+      vec![Str("hello (nested) world".to_string()), Int(1)]
+    );
+  }
+
+  #[test]
+  fn test_defn() {
+    let src = r#"
+/square { dup * } ( x -- x2 ) defn
+/vec2sqlen { square exch square + } ( x y -- len ) defn
+1 2 vec2sqlen
+/vec2sqlen load"#;
+    let mut vm = Vm::new();
+    vm.set_debug(true);
+    vm.parse_batch(Cursor::new(src)).unwrap();
+    vm.eval_all().unwrap();
+    let [Int(5), Block(block)] = vm.get_stack() else {
+      panic!("Unexpected stack: {:?}", vm.get_stack());
+    };
+    assert_eq!(
+      block.effect().map(|s| s.to_string()),
+      Some("( x y -- len )".to_string())
+    );
+    assert_eq!(
+      vm.signature("square").map(|s| s.to_string()),
+      Some("( x -- x2 )".to_string())
+    );
+  }
+
+  #[test]
+  fn test_defn_check() {
+    assert!(parse_err("/f { + } ( x y -- z ) defn 1 f")
+      .starts_with(
+      "<input>:1:30: \"f\" ( x y -- z ) expects 2 argument(s)"
+    ));
+    assert!(parse_err("/f { dup } ( x -- y ) defn 1 f")
+      .starts_with(
+      "<input>:1:10: \"f\" ( x -- y ) should return 1 value(s)"
+    ));
+  }
+
+  #[test]
+  fn test_error_location() {
+    let mut vm = Vm::new();
+    vm.parse_source(
+      "fib.txt",
+      Cursor::new("/f { 1 2 +\n  fbi } def\nf puts"),
+    )
+    .unwrap();
+    let err = vm.eval_all().unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "fib.txt:2:3: \"fbi\" is not a defined operation"
+    );
+    assert_eq!(
+      err.render(false),
+      r#"error: "fbi" is not a defined operation
+ --> fib.txt:2:3
+  |
+2 |   fbi } def
+  |   ^^^
+  in f called at fib.txt:3:1
+  in root
+"#
+    );
+  }
+
+  #[test]
+  fn test_parse_error() {
+    let mut vm = Vm::new();
+    let err =
+      vm.parse_batch(Cursor::new("1 2 }\n3")).unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::UnmatchedClose);
+    assert_eq!(err.to_string(), "<input>:1:5: unmatched \"}\"");
+
+    let err = vm
+      .parse_batch(Cursor::new("/f {\n  { 1 } def"))
+      .unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::UnclosedBlock);
+    assert_eq!(
+      err.render(false),
+      r#"error: unclosed "{"
+ --> <input>:1:4
+  |
+1 | /f {
+  |    ^
+"#
+    );
+
+    let err = vm.parse_batch(Cursor::new("(abc")).unwrap_err();
+    assert_eq!(err.kind, ParseErrorKind::UnclosedString);
+
+    let err = vm
+      .parse_batch(Cursor::new(b"1 \xff".as_slice()))
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "failed to read the source: invalid data"
+    );
+
+    // The Vm is still usable after errors
+    vm.parse_batch(Cursor::new("1 2 +")).unwrap();
+    vm.eval_all().unwrap();
+    assert_eq!(vm.get_stack(), &[Int(3)]);
+  }
+
+  #[test]
+  fn test_reentrant() {
+    let mut vm = Vm::new();
+    vm.parse_batch(Cursor::new("/x 10 def 1")).unwrap();
+    vm.eval_all().unwrap();
+    vm.parse_batch(Cursor::new("x 2 +")).unwrap();
+    vm.eval_all().unwrap();
+    assert_eq!(vm.get_stack(), &[Int(1), Int(12)]);
+    assert_eq!(vm.root_vars().get("x"), Some(&Int(10)));
+  }
+
+  #[test]
+  fn test_eval_source() {
+    let mut vm = Vm::new();
+    vm.eval_source("a", Cursor::new("/double { 2 * } def"))
+      .unwrap();
+    let err = vm
+      .eval_source("b", Cursor::new("1 undefined 2"))
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "b:1:3: \"undefined\" is not a defined operation"
+    );
+    // The failed unit does not resume in the next call
+    vm.eval_source("c", Cursor::new("10 double")).unwrap();
+    assert_eq!(vm.get_stack(), &[Int(1), Int(20)]);
+  }
+
+  #[test]
+  fn test_eval_source_paused() {
+    let mut vm = Vm::new();
+    vm.parse_batch(Cursor::new("1 2 + /y 3 def")).unwrap();
+    vm.eval_step().unwrap();
+    vm.eval_source("a", Cursor::new("/x 10 def undefined"))
+      .unwrap_err();
+    // The paused unit is intact
+    vm.eval_all().unwrap();
+    assert_eq!(vm.get_stack(), &[Int(3)]);
+    assert_eq!(vm.root_vars().get("x"), Some(&Int(10)));
+    assert_eq!(vm.root_vars().get("y"), Some(&Int(3)));
+  }
+
+  #[test]
+  fn test_include() {
+    let mut loader = MemoryLoader::new();
+    loader.add_file(
+      "lib/geom.txt",
+      "(util.txt) include /vec2sqlen { square exch square + } def"
+        .to_string(),
+    );
+    loader.add_file(
+      "lib/util.txt",
+      "/square { dup * } def 100".to_string(),
+    );
+    let mut vm = Vm::new();
+    vm.set_file_loader(Box::new(loader));
+    vm.eval_source(
+      "main.txt",
+      Cursor::new(
+        "(lib/geom.txt) include (lib/util.txt) include 1 2 vec2sqlen",
+      ),
+    )
+    .unwrap();
+    // util.txt is executed only once
+    assert_eq!(vm.get_stack(), &[Int(100), Int(5)]);
+
+    vm.eval_source(
+      "main.txt",
+      Cursor::new("(lib/util.txt) run"),
+    )
+    .unwrap();
+    assert_eq!(vm.get_stack(), &[Int(100), Int(5), Int(100)]);
+  }
+
+  #[test]
+  fn test_include_cycle() {
+    let mut loader = MemoryLoader::new();
+    loader.add_file("a.txt", "(b.txt) include".to_string());
+    loader.add_file("b.txt", "(a.txt) run".to_string());
+    let mut vm = Vm::new();
+    vm.set_file_loader(Box::new(loader));
+    let err = vm
+      .eval_source("a.txt", Cursor::new("(b.txt) include"))
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "b.txt:1:9: Include cycle detected: a.txt -> b.txt -> a.txt"
+    );
+  }
+
+  #[test]
+  fn test_module() {
+    let mut loader = MemoryLoader::new();
+    loader.add_file(
+      "geom.txt",
+      r#"
+/square { dup * } def
+/vec2sqlen { square exch square + } def
+/vec2sqlen export"#
+        .to_string(),
+    );
+    loader.add_file(
+      "lib/other.txt",
+      "/square { 0 } def /square export".to_string(),
+    );
+    let mut vm = Vm::new();
+    vm.set_file_loader(Box::new(loader));
+    vm.eval_source(
+      "main.txt",
+      Cursor::new(
+        r#"(geom) import (lib/other) (square) importfrom
+1 2 geom.vec2sqlen 3 square"#,
+      ),
+    )
+    .unwrap();
+    assert_eq!(vm.get_stack(), &[Int(5), Int(3), Int(0)]);
+
+    // Private names are not visible from the importer
+    let err = vm
+      .eval_source("main.txt", Cursor::new("3 geom.square"))
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "main.txt:1:3: \"geom.square\" is not a defined operation"
+    );
+    let err = vm
+      .eval_source(
+        "main.txt",
+        Cursor::new("(geom) (square) importfrom"),
+      )
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "main.txt:1:17: Module \"geom.txt\" does not export \"square\""
+    );
+  }
+
+  #[test]
+  fn test_prelude() {
+    let run = |src: &str| {
+      let mut vm = Vm::with_prelude();
+      vm.set_debug(true);
+      vm.eval_source("test", Cursor::new(src)).unwrap();
+      vm.get_stack().to_vec()
+    };
+    assert_eq!(run("3 double"), vec![Int(6)]);
+    assert_eq!(run("3 square"), vec![Int(9)]);
+    assert_eq!(run("-3 abs 4 abs"), vec![Int(3), Int(4)]);
+    assert_eq!(run("3 4 max 4 3 max"), vec![Int(4), Int(4)]);
+    assert_eq!(run("3 4 min 4 3 min"), vec![Int(3), Int(3)]);
+    assert_eq!(run("1 2 vec2sqlen"), vec![Int(5)]);
+    assert_eq!(
+      run("0 3 { 10 * } recfor"),
+      vec![Int(0), Int(10), Int(20)]
+    );
+    assert!(Vm::new().signature("square").is_none());
+    // Definitions in scripts shadow the prelude
+    assert_eq!(
+      run("/square { 0 } def 3 square"),
+      vec![Int(3), Int(0)]
+    );
+
+    // Loading the prelude leaves a paused execution as it was
+    let mut vm = Vm::new();
+    vm.parse_batch(Cursor::new("1 2 +")).unwrap();
+    vm.eval_step().unwrap();
+    vm.load_prelude();
+    vm.eval_all().unwrap();
+    assert_eq!(vm.get_stack(), &[Int(3)]);
+    assert!(vm.get_global("square").is_some());
+  }
+
+  #[test]
+  fn test_call() {
+    let mut vm = Vm::with_prelude();
+    assert_eq!(
+      vm.call("vec2sqlen", &[Int(1), Int(2)]),
+      Ok(vec![Int(5)])
+    );
+    vm.eval_source(
+      "a",
+      Cursor::new("/f { 10 + } def /g { 1 2 call_f } def"),
+    )
+    .unwrap();
+    assert_eq!(
+      vm.call("+", &[Int(1), Int(2)]),
+      Ok(vec![Int(3)])
+    );
+    // Call back into the script from a native function
+    vm.add_fn(
+      "call_f".to_string(),
+      Box::new(|vm| {
+        let arg = vm.pop().unwrap();
+        let res = vm.call("f", &[arg]).unwrap();
+        vm.push(res[0].clone());
+      }),
+    );
+    assert_eq!(vm.call("g", &[]), Ok(vec![Int(1), Int(12)]));
+    assert_eq!(
+      vm.call("h", &[]).map_err(|e| e.to_string()),
+      Err("\"h\" is not a defined operation".to_string())
+    );
+    assert_eq!(
+      vm.call("f", &[Str("a".to_string())])
+        .map_err(|e| e.to_string()),
+      Err(
+        "a:1:9: Binary arithmetic between incompatible types \
+          stringtype and integertype"
+          .to_string()
+      )
+    );
+    assert!(vm.get_stack().is_empty());
+    assert!(vm.get_exec_stack().is_empty());
+    vm.push(Int(1));
+    assert_eq!(
+      vm.call("pop", &[]).map_err(|e| e.to_string()),
+      Err("\"pop\" consumed below its arguments".to_string())
+    );
+  }
+
+  #[test]
+  fn test_globals() {
+    let mut vm = Vm::new();
+    assert!(vm.get_vars().is_empty());
+    vm.set_global("scale", Int(3));
+    vm.eval_source(
+      "a",
+      Cursor::new("/result scale 2 * def /scale 10 def"),
+    )
+    .unwrap();
+    assert_eq!(vm.root_vars().get("result"), Some(&Int(6)));
+    assert_eq!(vm.get_vars().get("result"), Some(&Int(6)));
+    // The root-level definition shadows the global.
+    assert_eq!(vm.get_global("scale"), Some(&Int(3)));
+    assert_eq!(vm.lookup("scale"), Some(Int(10)));
+    assert!(vm.globals().any(|(name, _)| name == "dup"));
+    assert_eq!(vm.remove_global("scale"), Some(Int(3)));
+    assert_eq!(vm.get_global("scale"), None);
+  }
+
+  #[test]
+  fn test_fork() {
+    let mut template = Vm::with_prelude();
+    template
+      .eval_source("lib", Cursor::new("/triple { 3 * } def"))
+      .unwrap();
+    assert_eq!(
+      run_in(&mut template.fork(), "2 triple"),
+      Ok(vec![Int(6)])
+    );
+    assert_eq!(
+      run_in(&mut template.fork(), "2 square"),
+      Ok(vec![Int(4)])
+    );
+    assert!(template.get_stack().is_empty());
+    // The code of the blocks is shared, not copied
+    let block = |vm: &Vm| match vm.root_vars().get("triple") {
+      Some(Block(block)) => block.block.clone(),
+      value => panic!("{value:?}"),
+    };
+    let fork = template.fork();
+    assert!(Shared::ptr_eq(&block(&template), &block(&fork)));
+    let out = OutputBuffer::new();
+    let mut fork = template.fork_with(
+      Box::new(Cursor::new("7\n")),
+      Box::new(out.clone()),
+      Box::new(std::io::sink()),
+    );
+    assert_eq!(
+      run_in(&mut fork, "read pop triple dup puts"),
+      Ok(vec![Int(21)])
+    );
+    assert_eq!(out.contents(), "21\n");
+  }
+
+  #[cfg(feature = "sync")]
+  #[test]
+  fn test_send() {
+    let mut template = Vm::with_prelude();
+    template
+      .eval_source("lib", Cursor::new("/triple { 3 * } def"))
+      .unwrap();
+    let handles: Vec<_> = (0..4)
+      .map(|i| {
+        let mut vm = template.fork();
+        std::thread::spawn(move || {
+          vm.call("triple", &[Int(i)]).unwrap()
+        })
+      })
+      .collect();
+    let res: Vec<_> =
+      handles.into_iter().map(|h| h.join().unwrap()).collect();
+    assert_eq!(
+      res,
+      vec![
+        vec![Int(0)],
+        vec![Int(3)],
+        vec![Int(6)],
+        vec![Int(9)]
+      ]
+    );
+  }
 }
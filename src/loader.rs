@@ -0,0 +1,114 @@
+use std::collections::HashMap;
+
+/// A source of script files for `include` and `run`.
+///
+/// Paths are `/`-separated strings, so that a loader does not have
+/// to be backed by a real file system.
+pub trait FileLoader {
+  fn load(&self, path: &str) -> Result<String, String>;
+
+  /// Resolves `path` relative to the file `base` which contains the
+  /// `include`, if any. The result is used as the key to cache the
+  /// loaded file, so it should be normalized.
+  fn resolve(&self, base: Option<&str>, path: &str) -> String {
+    resolve_path(base, path)
+  }
+}
+
+/// Loads files from the file system.
+pub struct FsLoader;
+
+impl FileLoader for FsLoader {
+  fn load(&self, path: &str) -> Result<String, String> {
+    std::fs::read_to_string(path)
+      .map_err(|e| format!("Failed to load {path:?}: {e}"))
+  }
+}
+
+/// Serves files from an in-memory map, e.g. in a browser.
+#[derive(Default)]
+pub struct MemoryLoader {
+  pub files: HashMap<String, String>,
+}
+
+impl MemoryLoader {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  pub fn add_file(&mut self, path: &str, text: String) {
+    self.files.insert(resolve_path(None, path), text);
+  }
+}
+
+impl FileLoader for MemoryLoader {
+  fn load(&self, path: &str) -> Result<String, String> {
+    self
+      .files
+      .get(path)
+      .cloned()
+      .ok_or_else(|| format!("File {path:?} is not found"))
+  }
+}
+
+/// Joins `path` to the directory of `base` and removes `.` and `..`
+/// components lexically.
+pub fn resolve_path(base: Option<&str>, path: &str) -> String {
+  let absolute = path.starts_with('/');
+  let dir = match base {
+    Some(base) if !absolute => {
+      base.rsplit_once('/').map_or("", |(dir, _)| dir)
+    }
+    _ => "",
+  };
+  let mut components: Vec<&str> = vec![];
+  for component in dir.split('/').chain(path.split('/')) {
+    match component {
+      "" | "." => (),
+      ".." => {
+        if matches!(components.last(), None | Some(&"..")) {
+          components.push("..");
+        } else {
+          components.pop();
+        }
+      }
+      _ => components.push(component),
+    }
+  }
+  let joined = components.join("/");
+  if absolute || dir.starts_with('/') {
+    format!("/{joined}")
+  } else {
+    joined
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_resolve_path() {
+    assert_eq!(resolve_path(None, "./a.txt"), "a.txt");
+    assert_eq!(
+      resolve_path(Some("lib/b.txt"), "c.txt"),
+      "lib/c.txt"
+    );
+    assert_eq!(
+      resolve_path(Some("lib/b.txt"), "../c.txt"),
+      "c.txt"
+    );
+    assert_eq!(
+      resolve_path(Some("b.txt"), "../c.txt"),
+      "../c.txt"
+    );
+    assert_eq!(
+      resolve_path(Some("/usr/b.txt"), "x/./c.txt"),
+      "/usr/x/c.txt"
+    );
+    assert_eq!(
+      resolve_path(Some("lib/b.txt"), "/c.txt"),
+      "/c.txt"
+    );
+  }
+}
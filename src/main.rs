@@ -1,21 +1,40 @@
-use std::error::Error;
+use std::{error::Error, io::IsTerminal};
 
-use ::rustack::Vm;
+use ::rustack::{FsLoader, Vm};
 
 pub fn main() -> Result<(), Box<dyn Error>> {
   let mut file_name = None;
+  let mut prelude = true;
+  let mut fs = true;
   for arg in std::env::args().skip(1) {
-    file_name = Some(arg);
+    if arg == "--no-prelude" {
+      prelude = false;
+    } else if arg == "--no-fs" {
+      fs = false;
+    } else {
+      file_name = Some(arg);
+    }
   }
   let Some(file_name) = file_name else {
-    eprintln!("usage: rustack [file_name.txt]");
+    eprintln!(
+      "usage: rustack [--no-prelude] [--no-fs] [file_name.txt]"
+    );
     return Ok(());
   };
-  let src = std::fs::read_to_string(file_name)?;
-  let mut vm = Vm::new();
-  vm.parse_batch(std::io::Cursor::new(src));
-  if let Err(e) = vm.eval_all() {
-    eprintln!("ERROR: {e}");
+  let src = std::fs::read_to_string(&file_name)?;
+  let mut builder = Vm::builder().prelude(prelude);
+  if fs {
+    // Scripts can access the files under the working directory,
+    // and include any file.
+    builder = builder
+      .fs_root(std::env::current_dir()?)
+      .file_loader(FsLoader);
+  }
+  let mut vm = builder.build();
+  if let Err(e) =
+    vm.eval_source(&file_name, std::io::Cursor::new(src))
+  {
+    eprint!("{}", e.render(std::io::stderr().is_terminal()));
     return Ok(());
   };
   format!("stack: {:?}\n", vm.get_stack());
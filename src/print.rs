@@ -0,0 +1,285 @@
+//! Printing and string formatting operators.
+
+use std::io::Write;
+
+use crate::{Value, Vm};
+
+/// Returns the syntactic representation of a value used by `==`
+/// and `pstack`, e.g. `(text)` for a string and `/name` for a
+/// symbol. Blocks are shown as their source text.
+fn repr(vm: &Vm, value: &Value) -> String {
+  match value {
+    Value::Str(s) => format!("({s})"),
+    Value::Sym(s) => format!("/{s}"),
+    Value::Block(block) => vm
+      .source_map
+      .file(block.span.0)
+      .and_then(|file| {
+        file.text.get(
+          block.span.0 - file.start..block.span.1 - file.start,
+        )
+      })
+      .map_or_else(|| value.to_string(), |s| s.to_string()),
+    Value::Array(items) => {
+      let items: Vec<_> =
+        items.iter().map(|item| repr(vm, item)).collect();
+      format!("[{}]", items.join(" "))
+    }
+    Value::Dict(entries) => {
+      let entries: Vec<_> = entries
+        .iter()
+        .map(|(key, value)| {
+          format!("/{key} {}", repr(vm, value))
+        })
+        .collect();
+      format!("<<{}>>", entries.join(" "))
+    }
+    _ => value.to_string(),
+  }
+}
+
+pub(crate) fn puts(vm: &mut Vm) -> Result<(), String> {
+  let value = vm.try_pop()?;
+  vm.write_output(&format!("{}\n", value.to_string()))
+}
+
+/// Prints a value without a trailing newline.
+pub(crate) fn print(vm: &mut Vm) -> Result<(), String> {
+  let value = vm.try_pop()?;
+  vm.write_output(&value.to_string())?;
+  vm.output
+    .flush()
+    .map_err(|e| format!("Failed to write output: {e}"))
+}
+
+/// `=`: prints the text of a value and a newline.
+pub(crate) fn print_text(vm: &mut Vm) -> Result<(), String> {
+  puts(vm)
+}
+
+/// `==`: prints the syntactic representation of a value.
+pub(crate) fn print_repr(vm: &mut Vm) -> Result<(), String> {
+  let value = vm.try_pop()?;
+  let text = repr(vm, &value);
+  vm.write_output(&(text + "\n"))
+}
+
+/// Prints the whole stack from the top without consuming it.
+pub(crate) fn pstack(vm: &mut Vm) -> Result<(), String> {
+  let text: String = vm
+    .stack
+    .iter()
+    .rev()
+    .map(|value| repr(vm, value) + "\n")
+    .collect();
+  vm.write_output(&text)
+}
+
+/// Like `pstack`, but prints values in the form of `=`.
+pub(crate) fn stack(vm: &mut Vm) -> Result<(), String> {
+  let text: String = vm
+    .stack
+    .iter()
+    .rev()
+    .map(|value| value.to_string() + "\n")
+    .collect();
+  vm.write_output(&text)
+}
+
+enum Placeholder {
+  /// `{}` or `%s`
+  Text,
+  /// `%d`
+  Int,
+  /// `%f` or `%.3f`
+  Real(usize),
+}
+
+enum Piece<'a> {
+  Literal(&'a str),
+  Placeholder(Placeholder),
+}
+
+fn parse_format(fmt: &str) -> Result<Vec<Piece<'_>>, String> {
+  let mut pieces = vec![];
+  let mut rest = fmt;
+  while let Some(pos) = rest.find(['{', '%']) {
+    if pos > 0 {
+      pieces.push(Piece::Literal(&rest[..pos]));
+    }
+    let spec = &rest[pos..];
+    let (piece, len) = if spec.starts_with("{}") {
+      (Piece::Placeholder(Placeholder::Text), 2)
+    } else if spec.starts_with("%%") {
+      (Piece::Literal("%"), 2)
+    } else if spec.starts_with("%s") {
+      (Piece::Placeholder(Placeholder::Text), 2)
+    } else if spec.starts_with("%d") {
+      (Piece::Placeholder(Placeholder::Int), 2)
+    } else if spec.starts_with("%f") {
+      (Piece::Placeholder(Placeholder::Real(6)), 2)
+    } else if let Some(prec) = spec.strip_prefix("%.") {
+      let digits = prec
+        .find(|c: char| !c.is_ascii_digit())
+        .unwrap_or(prec.len());
+      if digits == 0 || !prec[digits..].starts_with('f') {
+        return Err(format!(
+          "Invalid format specifier in {fmt:?}"
+        ));
+      }
+      let prec = prec[..digits]
+        .parse()
+        .map_err(|_| format!("Invalid precision in {fmt:?}"))?;
+      (Piece::Placeholder(Placeholder::Real(prec)), digits + 3)
+    } else if spec.starts_with('{') {
+      (Piece::Literal("{"), 1)
+    } else {
+      return Err(format!(
+        "Invalid format specifier in {fmt:?}"
+      ));
+    };
+    pieces.push(piece);
+    rest = &spec[len..];
+  }
+  if !rest.is_empty() {
+    pieces.push(Piece::Literal(rest));
+  }
+  Ok(pieces)
+}
+
+/// `args... (fmt) format` replaces the placeholders in `fmt` with
+/// the arguments, which are consumed from the stack in the order
+/// they were pushed, and pushes the resulting string.
+pub(crate) fn format(vm: &mut Vm) -> Result<(), String> {
+  // Validate everything before popping, so that a failure leaves
+  // the stack as it was
+  let fmt = match vm.stack.last() {
+    Some(Value::Str(s)) => s.clone(),
+    Some(value) => {
+      return Err(format!(
+        "format expects a format string, got {}",
+        value.type_name()
+      ))
+    }
+    None => return Err("Stack underflow".to_string()),
+  };
+  let pieces = parse_format(&fmt)?;
+  let argc = pieces
+    .iter()
+    .filter(|piece| matches!(piece, Piece::Placeholder(_)))
+    .count();
+  let depth = vm.stack.len() - 1;
+  if depth < argc {
+    return Err(format!(
+      "{fmt:?} expects {argc} argument(s), but the stack has {depth}"
+    ));
+  }
+  let base = depth - argc;
+  let mut args = vm.stack[base..depth].iter();
+  let mut ret = String::new();
+  for piece in &pieces {
+    match piece {
+      Piece::Literal(s) => ret += s,
+      Piece::Placeholder(placeholder) => {
+        let arg = args.next().unwrap();
+        match placeholder {
+          Placeholder::Text => ret += &arg.to_string(),
+          Placeholder::Int => {
+            ret += &(arg.try_num()?.trunc() as i32).to_string()
+          }
+          Placeholder::Real(prec) => {
+            ret += &format!("{:.*}", prec, arg.try_num()?)
+          }
+        }
+      }
+    }
+  }
+  vm.stack.truncate(base);
+  vm.stack.push(Value::Str(ret));
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test::run;
+  use std::io::Cursor;
+
+  fn string(s: &str) -> Result<Vec<Value>, String> {
+    Ok(vec![Value::Str(s.to_string())])
+  }
+
+  #[test]
+  fn test_format() {
+    assert_eq!(
+      run("1 2 3 ({} + {} = %d) format"),
+      string("1 + 2 = 3")
+    );
+    assert_eq!(
+      run("3.14159 (pi = %.3f) format"),
+      string("pi = 3.142")
+    );
+    assert_eq!(
+      run("2.9 (/name) (%d%% %s) format"),
+      string("2% /name")
+    );
+    assert_eq!(
+      run("1 ({} {}) format"),
+      Err(
+        "<input>:1:11: \"{} {}\" expects 2 argument(s), \
+          but the stack has 1"
+          .to_string()
+      )
+    );
+
+    // A failed format leaves its operands on the stack
+    let mut vm = Vm::new();
+    let err = vm
+      .eval_source("a", Cursor::new("/x 2 (%d %d) format"))
+      .unwrap_err();
+    assert_eq!(
+      err.to_string(),
+      "a:1:14: Expected a number, got nametype"
+    );
+    assert_eq!(
+      vm.get_stack(),
+      [
+        Value::Sym("x".to_string()),
+        Value::Int(2),
+        Value::Str("%d %d".to_string())
+      ]
+    );
+  }
+
+  #[test]
+  fn test_output() {
+    let out = crate::OutputBuffer::new();
+    let mut vm = Vm::builder().output(out.clone()).build();
+    vm.eval_source(
+      "a",
+      Cursor::new(
+        "1 print 2 print 3 puts (s) = (s) == 1.5 /x pstack",
+      ),
+    )
+    .unwrap();
+    assert_eq!(out.take(), "123\ns\n(s)\n/x\n1.5\n");
+    vm.eval_source("b", Cursor::new("stack")).unwrap();
+    assert_eq!(out.contents(), "x\n1.5\n");
+  }
+
+  #[test]
+  fn test_repr() {
+    let mut vm = Vm::new();
+    vm.parse_batch(Cursor::new(
+      "(s) /a { 1 2 + } [ (t) << /k /v >> ]",
+    ))
+    .unwrap();
+    vm.eval_all().unwrap();
+    let reprs: Vec<_> =
+      vm.stack.iter().map(|value| repr(&vm, value)).collect();
+    assert_eq!(
+      reprs,
+      ["(s)", "/a", "{ 1 2 + }", "[(t) <</k /v>>]"]
+    );
+  }
+}
@@ -0,0 +1,787 @@
+//! Saving the state of a `Vm` to bytes and restoring it, so that a
+//! paused script can be resumed later or on another machine.
+//!
+//! The format starts with `RSTK` and the version number followed
+//! by the sources, the operand stack, the execution stack and the
+//! variables. Integers are little endian and lengths are `u64`.
+//!
+//! Native functions cannot be saved, so they are written by the name
+//! of the global which holds them and looked up in the restoring
+//! `Vm`, which must have the same host functions registered. Files
+//! and host objects cannot be saved at all.
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::{
+  BlockSpan, ExecFrame, ExecState, Import, Module, Shared,
+  SourceMap, StackEffect, Value, ValueSpan, Vm,
+};
+
+const MAGIC: &[u8; 4] = b"RSTK";
+
+/// Incremented on every incompatible change of the format.
+pub const SNAPSHOT_VERSION: u32 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SnapshotError {
+  /// A value of the type cannot be saved, like a file.
+  Unsupported(&'static str),
+  /// A native function is not held by any global, so it cannot be
+  /// re-bound by name.
+  UnnamedNative,
+  /// The restoring `Vm` has no native function of the name.
+  MissingNative(String),
+  /// The snapshot was saved in another version of the format.
+  Version(u32),
+  /// The data is not a snapshot or is truncated.
+  Corrupted,
+}
+
+impl std::fmt::Display for SnapshotError {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    match self {
+      Self::Unsupported(type_name) => {
+        write!(f, "Cannot save a value of {type_name}")
+      }
+      Self::UnnamedNative => write!(
+        f,
+        "Cannot save a native function which is not a global"
+      ),
+      Self::MissingNative(name) => {
+        write!(f, "Native function {name:?} is not registered")
+      }
+      Self::Version(version) => write!(
+        f,
+        "Snapshot version {version} is not supported, \
+        expected {SNAPSHOT_VERSION}"
+      ),
+      Self::Corrupted => write!(f, "Snapshot is corrupted"),
+    }
+  }
+}
+
+impl std::error::Error for SnapshotError {}
+
+type Result<T> = std::result::Result<T, SnapshotError>;
+
+/// Returns an identity of a native function to find its name.
+fn native_id(value: &Value) -> Option<usize> {
+  match value {
+    Value::Native(op) => {
+      Some(crate::Shared::as_ptr(&op.0) as *const u8 as usize)
+    }
+    _ => None,
+  }
+}
+
+/// Sorts variables by name so that the same state is always saved
+/// to the same bytes.
+fn sorted(
+  vars: &HashMap<String, Value>,
+) -> BTreeMap<&String, &Value> {
+  vars.iter().collect()
+}
+
+struct Writer {
+  buf: Vec<u8>,
+  /// Names of the globals holding native functions, by identity
+  natives: HashMap<usize, String>,
+}
+
+impl Writer {
+  fn u8(&mut self, v: u8) {
+    self.buf.push(v);
+  }
+
+  fn u32(&mut self, v: u32) {
+    self.buf.extend_from_slice(&v.to_le_bytes());
+  }
+
+  fn usize(&mut self, v: usize) {
+    self.buf.extend_from_slice(&(v as u64).to_le_bytes());
+  }
+
+  fn str(&mut self, s: &str) {
+    self.usize(s.len());
+    self.buf.extend_from_slice(s.as_bytes());
+  }
+
+  fn strs(&mut self, strs: &[String]) {
+    self.usize(strs.len());
+    for s in strs {
+      self.str(s);
+    }
+  }
+
+  fn span(&mut self, span: (usize, usize)) {
+    self.usize(span.0);
+    self.usize(span.1);
+  }
+
+  fn value(&mut self, value: &Value) -> Result<()> {
+    match value {
+      Value::Int(i) => {
+        self.u8(0);
+        self.buf.extend_from_slice(&i.to_le_bytes());
+      }
+      Value::Num(f) => {
+        self.u8(1);
+        self.buf.extend_from_slice(&f.to_le_bytes());
+      }
+      Value::Op(s) => {
+        self.u8(2);
+        self.str(s);
+      }
+      Value::Sym(s) => {
+        self.u8(3);
+        self.str(s);
+      }
+      Value::Str(s) => {
+        self.u8(4);
+        self.str(s);
+      }
+      Value::Block(block) => {
+        self.u8(5);
+        self.block(block)?;
+      }
+      Value::Native(_) => {
+        let name = native_id(value)
+          .and_then(|id| self.natives.get(&id))
+          .ok_or(SnapshotError::UnnamedNative)?
+          .clone();
+        self.u8(6);
+        self.str(&name);
+      }
+      Value::Mark => self.u8(7),
+      Value::Module(path) => {
+        self.u8(8);
+        self.str(path);
+      }
+      Value::Array(items) => {
+        self.u8(9);
+        self.values(items)?;
+      }
+      Value::Dict(entries) => {
+        self.u8(10);
+        self.usize(entries.len());
+        for (key, value) in entries {
+          self.str(key);
+          self.value(value)?;
+        }
+      }
+      Value::File(_) | Value::Host(_) => {
+        return Err(SnapshotError::Unsupported(
+          value.type_name(),
+        ))
+      }
+    }
+    Ok(())
+  }
+
+  fn values(&mut self, values: &[Value]) -> Result<()> {
+    self.usize(values.len());
+    for value in values {
+      self.value(value)?;
+    }
+    Ok(())
+  }
+
+  fn vars(
+    &mut self,
+    vars: &HashMap<String, Value>,
+  ) -> Result<()> {
+    self.usize(vars.len());
+    for (name, value) in sorted(vars) {
+      self.str(name);
+      self.value(value)?;
+    }
+    Ok(())
+  }
+
+  fn block(&mut self, block: &BlockSpan) -> Result<()> {
+    self.span(block.span);
+    self.usize(block.block.len());
+    for value_span in block.block.iter() {
+      self.span(value_span.span);
+      self.value(&value_span.value)?;
+    }
+    match &block.effect {
+      Some(effect) => {
+        self.u8(1);
+        self.strs(&effect.inputs);
+        self.strs(&effect.outputs);
+      }
+      None => self.u8(0),
+    }
+    Ok(())
+  }
+
+  fn frame(&mut self, frame: &ExecFrame) -> Result<()> {
+    self.str(&frame.name);
+    self.block(&frame.block)?;
+    self.usize(frame.ip);
+    self.vars(&frame.vars)?;
+    match frame.stack_base {
+      Some(base) => {
+        self.u8(1);
+        self.usize(base);
+      }
+      None => self.u8(0),
+    }
+    self.u8(frame.root as u8);
+    Ok(())
+  }
+
+  fn state(&mut self, state: &ExecState) -> Result<()> {
+    match state {
+      ExecState::Frame(frame) => {
+        self.u8(0);
+        self.frame(frame)
+      }
+      ExecState::IfCond {
+        frame,
+        true_branch,
+        false_branch,
+      } => {
+        self.u8(1);
+        self.frame(frame)?;
+        self.block(true_branch)?;
+        self.block(false_branch)
+      }
+      ExecState::IfTrue(frame) => {
+        self.u8(2);
+        self.frame(frame)
+      }
+      ExecState::IfFalse(frame) => {
+        self.u8(3);
+        self.frame(frame)
+      }
+      ExecState::For { frame, i, end } => {
+        self.u8(4);
+        self.frame(frame)?;
+        self.buf.extend_from_slice(&i.to_le_bytes());
+        self.buf.extend_from_slice(&end.to_le_bytes());
+        Ok(())
+      }
+      ExecState::Include(frame) => {
+        self.u8(5);
+        self.frame(frame)
+      }
+      ExecState::Module {
+        frame,
+        import,
+        exports,
+      } => {
+        self.u8(6);
+        self.frame(frame)?;
+        self.import(import);
+        self.strs(exports);
+        Ok(())
+      }
+    }
+  }
+
+  fn import(&mut self, import: &Import) {
+    match import {
+      Import::Prefix(prefix) => {
+        self.u8(0);
+        self.str(prefix);
+      }
+      Import::Names(names) => {
+        self.u8(1);
+        self.strs(names);
+      }
+    }
+  }
+}
+
+/// How deeply values like arrays and blocks can be nested in a
+/// snapshot, so that a corrupted one cannot overflow the stack of
+/// the reader.
+const MAX_DEPTH: usize = 256;
+
+struct Reader<'a> {
+  data: &'a [u8],
+  /// The globals of the restoring `Vm` to look up natives in
+  globals: &'a HashMap<String, Value>,
+  /// The number of values being read which contain the current one
+  depth: usize,
+}
+
+impl<'a> Reader<'a> {
+  fn bytes(&mut self, len: usize) -> Result<&'a [u8]> {
+    if self.data.len() < len {
+      return Err(SnapshotError::Corrupted);
+    }
+    let (bytes, rest) = self.data.split_at(len);
+    self.data = rest;
+    Ok(bytes)
+  }
+
+  fn array<const N: usize>(&mut self) -> Result<[u8; N]> {
+    Ok(self.bytes(N)?.try_into().unwrap())
+  }
+
+  fn u8(&mut self) -> Result<u8> {
+    Ok(self.bytes(1)?[0])
+  }
+
+  fn bool(&mut self) -> Result<bool> {
+    match self.u8()? {
+      0 => Ok(false),
+      1 => Ok(true),
+      _ => Err(SnapshotError::Corrupted),
+    }
+  }
+
+  fn u32(&mut self) -> Result<u32> {
+    Ok(u32::from_le_bytes(self.array()?))
+  }
+
+  fn i32(&mut self) -> Result<i32> {
+    Ok(i32::from_le_bytes(self.array()?))
+  }
+
+  fn usize(&mut self) -> Result<usize> {
+    usize::try_from(u64::from_le_bytes(self.array()?))
+      .map_err(|_| SnapshotError::Corrupted)
+  }
+
+  /// Reads a length of a sequence whose items take at least a byte,
+  /// so that a corrupted length cannot allocate too much.
+  fn len(&mut self) -> Result<usize> {
+    let len = self.usize()?;
+    if self.data.len() < len {
+      return Err(SnapshotError::Corrupted);
+    }
+    Ok(len)
+  }
+
+  fn string(&mut self) -> Result<String> {
+    let len = self.len()?;
+    String::from_utf8(self.bytes(len)?.to_vec())
+      .map_err(|_| SnapshotError::Corrupted)
+  }
+
+  fn strings(&mut self) -> Result<Vec<String>> {
+    (0..self.len()?).map(|_| self.string()).collect()
+  }
+
+  fn span(&mut self) -> Result<(usize, usize)> {
+    Ok((self.usize()?, self.usize()?))
+  }
+
+  fn value(&mut self) -> Result<Value> {
+    if MAX_DEPTH <= self.depth {
+      return Err(SnapshotError::Corrupted);
+    }
+    self.depth += 1;
+    let value = self.value_inner();
+    self.depth -= 1;
+    value
+  }
+
+  fn value_inner(&mut self) -> Result<Value> {
+    Ok(match self.u8()? {
+      0 => Value::Int(self.i32()?),
+      1 => Value::Num(f32::from_le_bytes(self.array()?)),
+      2 => Value::Op(self.string()?),
+      3 => Value::Sym(self.string()?),
+      4 => Value::Str(self.string()?),
+      5 => Value::Block(self.block()?),
+      6 => {
+        let name = self.string()?;
+        match self.globals.get(&name) {
+          Some(value @ Value::Native(_)) => value.clone(),
+          _ => return Err(SnapshotError::MissingNative(name)),
+        }
+      }
+      7 => Value::Mark,
+      8 => Value::Module(self.string()?),
+      9 => Value::Array(self.values()?),
+      10 => {
+        let mut entries = BTreeMap::new();
+        for _ in 0..self.len()? {
+          entries.insert(self.string()?, self.value()?);
+        }
+        Value::Dict(entries)
+      }
+      _ => return Err(SnapshotError::Corrupted),
+    })
+  }
+
+  fn values(&mut self) -> Result<Vec<Value>> {
+    (0..self.len()?).map(|_| self.value()).collect()
+  }
+
+  fn vars(&mut self) -> Result<HashMap<String, Value>> {
+    (0..self.len()?)
+      .map(|_| Ok((self.string()?, self.value()?)))
+      .collect()
+  }
+
+  fn block(&mut self) -> Result<BlockSpan> {
+    let span = self.span()?;
+    let block = (0..self.len()?)
+      .map(|_| {
+        let span = self.span()?;
+        Ok(ValueSpan {
+          value: self.value()?,
+          span,
+        })
+      })
+      .collect::<Result<Vec<_>>>()?;
+    let effect = if self.bool()? {
+      Some(StackEffect {
+        inputs: self.strings()?,
+        outputs: self.strings()?,
+      })
+    } else {
+      None
+    };
+    Ok(BlockSpan {
+      block: Shared::new(block),
+      span,
+      effect,
+    })
+  }
+
+  fn frame(&mut self) -> Result<ExecFrame> {
+    let name = self.string()?;
+    let block = self.block()?;
+    let ip = self.usize()?;
+    let vars = self.vars()?;
+    let stack_base = if self.bool()? {
+      Some(self.usize()?)
+    } else {
+      None
+    };
+    let root = self.bool()?;
+    if block.block.len() < ip {
+      return Err(SnapshotError::Corrupted);
+    }
+    Ok(ExecFrame {
+      name,
+      block,
+      ip,
+      vars,
+      stack_base,
+      root,
+      module: None,
+    })
+  }
+
+  fn state(&mut self) -> Result<ExecState> {
+    Ok(match self.u8()? {
+      0 => ExecState::Frame(self.frame()?),
+      1 => ExecState::IfCond {
+        frame: self.frame()?,
+        true_branch: self.block()?,
+        false_branch: self.block()?,
+      },
+      2 => ExecState::IfTrue(self.frame()?),
+      3 => ExecState::IfFalse(self.frame()?),
+      4 => ExecState::For {
+        frame: self.frame()?,
+        i: self.i32()?,
+        end: self.i32()?,
+      },
+      5 => ExecState::Include(self.frame()?),
+      6 => ExecState::Module {
+        frame: self.frame()?,
+        import: match self.u8()? {
+          0 => Import::Prefix(self.string()?),
+          1 => Import::Names(self.strings()?),
+          _ => return Err(SnapshotError::Corrupted),
+        },
+        exports: self.strings()?,
+      },
+      _ => return Err(SnapshotError::Corrupted),
+    })
+  }
+}
+
+impl Vm {
+  /// Saves the state of the execution: the sources, the operand and
+  /// execution stacks, the variables, the loaded files and modules.
+  ///
+  /// The host configuration like the sandbox, the input and the
+  /// outputs is not saved, and native functions are saved by name.
+  /// It fails if the state has a file or a host object, or a native
+  /// function which is not held by a global.
+  pub fn snapshot(&self) -> Result<Vec<u8>> {
+    let mut natives = HashMap::new();
+    for (name, value) in &self.globals {
+      if let Some(id) = native_id(value) {
+        let entry =
+          natives.entry(id).or_insert_with(|| name.clone());
+        // Prefer the same name every time
+        if name < entry {
+          *entry = name.clone();
+        }
+      }
+    }
+    let mut w = Writer {
+      buf: MAGIC.to_vec(),
+      natives,
+    };
+    w.u32(SNAPSHOT_VERSION);
+
+    let files = self.source_map.files();
+    w.usize(files.len());
+    for file in files {
+      w.str(&file.name);
+      w.str(&file.text);
+    }
+
+    w.values(&self.stack)?;
+    w.usize(self.exec_stack.len());
+    for state in &self.exec_stack {
+      w.state(state)?;
+    }
+
+    // Natives are re-bound from the restoring `Vm`, so only the
+    // names are saved.
+    let mut native_globals: Vec<_> = self
+      .globals
+      .iter()
+      .filter(|(_, value)| matches!(value, Value::Native(_)))
+      .map(|(name, _)| name.clone())
+      .collect();
+    native_globals.sort();
+    w.strs(&native_globals);
+    let globals = self
+      .globals
+      .iter()
+      .filter(|(_, value)| !matches!(value, Value::Native(_)))
+      .map(|(name, value)| (name.clone(), value.clone()))
+      .collect();
+    w.vars(&globals)?;
+    w.vars(&self.root_vars)?;
+
+    w.usize(self.loaded_files.len());
+    for (path, block) in
+      self.loaded_files.iter().collect::<BTreeMap<_, _>>()
+    {
+      w.str(path);
+      w.block(block)?;
+    }
+    w.usize(self.modules.len());
+    for (path, module) in
+      self.modules.iter().collect::<BTreeMap<_, _>>()
+    {
+      w.str(path);
+      w.vars(&module.vars)?;
+      w.strs(&module.exports);
+    }
+
+    w.u8(self.debug as u8);
+    w.u32(self.random_state);
+    Ok(w.buf)
+  }
+
+  /// Restores a state saved by [`Vm::snapshot`], replacing the
+  /// execution state of this `Vm`. Resume it with
+  /// [`Vm::eval_all`] or [`Vm::eval_step`].
+  ///
+  /// This `Vm` should be configured like the saved one, since the
+  /// native functions are looked up by name in its globals. On
+  /// error, this `Vm` is left unchanged.
+  pub fn restore(&mut self, data: &[u8]) -> Result<()> {
+    let mut r = Reader {
+      data,
+      globals: &self.globals,
+      depth: 0,
+    };
+    if r.bytes(MAGIC.len()).ok() != Some(MAGIC) {
+      return Err(SnapshotError::Corrupted);
+    }
+    let version = r.u32()?;
+    if version != SNAPSHOT_VERSION {
+      return Err(SnapshotError::Version(version));
+    }
+
+    let mut source_map = SourceMap::new();
+    for _ in 0..r.len()? {
+      let name = r.string()?;
+      source_map.add_file(name, r.string()?);
+    }
+
+    let stack = r.values()?;
+    let exec_stack = (0..r.len()?)
+      .map(|_| r.state())
+      .collect::<Result<_>>()?;
+
+    let mut globals = HashMap::new();
+    for name in r.strings()? {
+      match self.globals.get(&name) {
+        Some(value @ Value::Native(_)) => {
+          globals.insert(name, value.clone());
+        }
+        _ => return Err(SnapshotError::MissingNative(name)),
+      }
+    }
+    globals.extend(r.vars()?);
+    let root_vars = r.vars()?;
+
+    let loaded_files = (0..r.len()?)
+      .map(|_| Ok((r.string()?, r.block()?)))
+      .collect::<Result<_>>()?;
+    let modules = (0..r.len()?)
+      .map(|_| {
+        let path = r.string()?;
+        let vars = r.vars()?;
+        let exports = r.strings()?;
+        Ok((path, Shared::new(Module { vars, exports })))
+      })
+      .collect::<Result<_>>()?;
+
+    let debug = r.bool()?;
+    let random_state = r.u32()?;
+    if !r.data.is_empty() {
+      return Err(SnapshotError::Corrupted);
+    }
+
+    self
+      .builtin_groups
+      .retain(|name, _| globals.contains_key(name));
+    self.stack = stack;
+    self.exec_stack = exec_stack;
+    self.globals = globals;
+    self.root_vars = root_vars;
+    self.blocks = vec![];
+    self.source_map = source_map;
+    self.loaded_files = loaded_files;
+    self.modules = modules;
+    // Frames refer to the modules defining their code, which are
+    // shared with `self.modules` again.
+    let modules: Vec<_> = self
+      .exec_stack
+      .iter()
+      .map(|state| {
+        self.module_at(state.as_frame().block.span.0)
+      })
+      .collect();
+    for (state, module) in
+      self.exec_stack.iter_mut().zip(modules)
+    {
+      state.as_frame_mut().module = module;
+    }
+    self.debug = debug;
+    self.random_state = random_state;
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::OutputBuffer;
+  use std::io::Cursor;
+
+  const SRC: &str = "/add3 { 3 + } def
+0 1 5 { add3 double /x exch def { x 10 < } { x } { 1 } if + } for
+(done) puts";
+
+  fn new_vm(out: &OutputBuffer) -> Vm {
+    let mut vm = Vm::builder().output(out.clone()).build();
+    vm.register("double", |x: i32| x * 2);
+    vm
+  }
+
+  #[test]
+  fn test_restore() {
+    let out = OutputBuffer::new();
+    let mut vm = new_vm(&out);
+    vm.parse_batch(Cursor::new(SRC)).unwrap();
+    let mut snapshots = vec![];
+    while vm.eval_step().unwrap().is_some() {
+      snapshots.push(vm.snapshot().unwrap());
+    }
+    let expected = vm.get_stack().to_vec();
+    assert_eq!(out.take(), "done\n");
+
+    // Resume from every step, including in `For` and `IfCond`
+    for snapshot in snapshots {
+      let mut vm = new_vm(&out);
+      vm.restore(&snapshot).unwrap();
+      vm.eval_all().unwrap();
+      assert_eq!(vm.get_stack(), expected);
+      assert_eq!(vm.snapshot().unwrap(), {
+        let mut other = new_vm(&out);
+        other.restore(&vm.snapshot().unwrap()).unwrap();
+        other.snapshot().unwrap()
+      });
+    }
+  }
+
+  #[test]
+  fn test_native() {
+    let out = OutputBuffer::new();
+    let mut vm = new_vm(&out);
+    vm.eval_source(
+      "a",
+      Cursor::new("/d /double load def 3 d /double load"),
+    )
+    .unwrap();
+    let snapshot = vm.snapshot().unwrap();
+
+    let mut restored = new_vm(&out);
+    restored.restore(&snapshot).unwrap();
+    restored.eval_source("b", Cursor::new("pop d")).unwrap();
+    assert_eq!(restored.get_stack(), [Value::Int(12)]);
+
+    assert_eq!(
+      Vm::new().restore(&snapshot),
+      Err(SnapshotError::MissingNative("double".to_string()))
+    );
+  }
+
+  #[test]
+  fn test_error() {
+    let mut vm = Vm::new();
+    vm.push(Value::Host(crate::HostValue::new("counter", 1)));
+    assert_eq!(
+      vm.snapshot(),
+      Err(SnapshotError::Unsupported("counter"))
+    );
+
+    let mut vm = Vm::new();
+    vm.push(Value::Native(crate::NativeOp(Shared::new(
+      Box::new(|_: &mut Vm| Ok(())),
+    ))));
+    assert_eq!(
+      vm.snapshot(),
+      Err(SnapshotError::UnnamedNative)
+    );
+
+    let mut snapshot = Vm::new().snapshot().unwrap();
+    assert_eq!(
+      Vm::new().restore(&snapshot[..10]),
+      Err(SnapshotError::Corrupted)
+    );
+    snapshot[4] = 0;
+    assert_eq!(
+      Vm::new().restore(&snapshot),
+      Err(SnapshotError::Version(0))
+    );
+
+    let restore_nested = |depth: usize| {
+      let nested = (1..depth)
+        .fold(Value::Int(1), |value, _| {
+          Value::Array(vec![value])
+        });
+      let mut vm = Vm::new();
+      vm.push(nested);
+      Vm::new().restore(&vm.snapshot().unwrap())
+    };
+    assert_eq!(restore_nested(MAX_DEPTH), Ok(()));
+    assert_eq!(
+      restore_nested(MAX_DEPTH + 1),
+      Err(SnapshotError::Corrupted)
+    );
+  }
+}
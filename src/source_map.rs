@@ -0,0 +1,164 @@
+/// A source file registered in a [`SourceMap`].
+#[derive(Debug, Clone)]
+pub struct SourceFile {
+  pub name: String,
+  /// The offset of the first byte of this file in the global span
+  /// space.
+  pub start: usize,
+  pub text: String,
+  line_starts: Vec<usize>,
+}
+
+impl SourceFile {
+  fn new(name: String, start: usize, text: String) -> Self {
+    let line_starts = std::iter::once(0)
+      .chain(
+        text
+          .bytes()
+          .enumerate()
+          .filter(|(_, b)| *b == b'\n')
+          .map(|(i, _)| i + 1),
+      )
+      .collect();
+    Self {
+      name,
+      start,
+      text,
+      line_starts,
+    }
+  }
+
+  pub fn end(&self) -> usize {
+    self.start + self.text.len()
+  }
+
+  /// Returns 0-based line index and the byte range of the line
+  /// containing the given offset relative to the file.
+  fn line_of(&self, offset: usize) -> (usize, usize, usize) {
+    let line = self
+      .line_starts
+      .partition_point(|start| *start <= offset)
+      .saturating_sub(1);
+    let start = self.line_starts[line];
+    let end = self
+      .line_starts
+      .get(line + 1)
+      .map(|next| next - 1)
+      .unwrap_or(self.text.len());
+    (line, start, end)
+  }
+
+  /// Returns the text of the line containing the given global
+  /// offset, without the line terminator.
+  pub fn line_text(&self, offset: usize) -> &str {
+    let (_, start, end) = self.line_of(offset - self.start);
+    self.text[start..end].trim_end_matches('\r')
+  }
+}
+
+/// A location in the source code, with 1-based line and column
+/// numbers.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+  pub file: String,
+  pub line: usize,
+  pub col: usize,
+}
+
+impl std::fmt::Display for Location {
+  fn fmt(
+    &self,
+    f: &mut std::fmt::Formatter<'_>,
+  ) -> std::fmt::Result {
+    write!(f, "{}:{}:{}", self.file, self.line, self.col)
+  }
+}
+
+use crate::Shared;
+
+/// Spans are byte offsets into a global space shared by all the
+/// source files loaded into a `Vm`. The first file starts at 0, so
+/// its spans are also the offsets into its own text.
+#[derive(Debug, Clone, Default)]
+pub struct SourceMap {
+  /// Shared so that forks of a `Vm` do not copy the sources
+  files: Vec<Shared<SourceFile>>,
+}
+
+impl SourceMap {
+  pub fn new() -> Self {
+    Self::default()
+  }
+
+  /// Registers a file and returns the offset of its first byte.
+  pub fn add_file(
+    &mut self,
+    name: String,
+    text: String,
+  ) -> usize {
+    // Leave a gap of one byte so that the end of a file and the
+    // start of the next one do not share an offset.
+    let start =
+      self.files.last().map(|file| file.end() + 1).unwrap_or(0);
+    self
+      .files
+      .push(Shared::new(SourceFile::new(name, start, text)));
+    start
+  }
+
+  pub fn files(&self) -> &[Shared<SourceFile>] {
+    &self.files
+  }
+
+  pub fn file(&self, offset: usize) -> Option<&SourceFile> {
+    let idx = self
+      .files
+      .partition_point(|file| file.start <= offset)
+      .checked_sub(1)?;
+    let file = &self.files[idx];
+    (offset <= file.end()).then_some(file)
+  }
+
+  /// Returns the location of an offset. An offset inside a
+  /// multi-byte character is located at the character.
+  pub fn lookup(&self, offset: usize) -> Option<Location> {
+    let file = self.file(offset)?;
+    let mut offset = offset - file.start;
+    while !file.text.is_char_boundary(offset) {
+      offset -= 1;
+    }
+    let (line, start, _) = file.line_of(offset);
+    Some(Location {
+      file: file.name.clone(),
+      line: line + 1,
+      col: file.text[start..offset].chars().count() + 1,
+    })
+  }
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+
+  #[test]
+  fn test_lookup() {
+    let mut map = SourceMap::new();
+    map.add_file(
+      "a.txt".to_string(),
+      "1 2 +\n3 puts\n".to_string(),
+    );
+    let b =
+      map.add_file("b.txt".to_string(), "fbi".to_string());
+    let c =
+      map.add_file("c.txt".to_string(), "(é) 1".to_string());
+    let loc =
+      |offset| map.lookup(offset).map(|l| l.to_string());
+    assert_eq!(loc(0).as_deref(), Some("a.txt:1:1"));
+    assert_eq!(loc(8).as_deref(), Some("a.txt:2:3"));
+    assert_eq!(loc(c + 2).as_deref(), Some("c.txt:1:2"));
+    assert_eq!(loc(c + 3).as_deref(), Some("c.txt:1:3"));
+    assert_eq!(loc(b + 1).as_deref(), Some("b.txt:1:2"));
+    assert_eq!(map.file(8).unwrap().line_text(8), "3 puts");
+    assert_eq!(loc(100), None);
+  }
+}
@@ -0,0 +1,224 @@
+//! Operand stack manipulation operators.
+//!
+//! All of them report an underflow as an error instead of panicking.
+
+use crate::{Value, Vm};
+
+/// Returns an error unless the stack has at least `n` values.
+fn require(vm: &Vm, n: usize) -> Result<(), String> {
+  if vm.stack.len() < n {
+    Err("Stack underflow".to_string())
+  } else {
+    Ok(())
+  }
+}
+
+/// Pops a non-negative count operand, which can be a real without
+/// a fractional part like `2.0`.
+fn pop_count(vm: &mut Vm) -> Result<usize, String> {
+  let value = vm.try_pop()?;
+  match value.try_int() {
+    Ok(n) if n >= 0 => Ok(n as usize),
+    _ => Err(format!(
+      "Expected a non-negative integer, got {}",
+      value.to_string()
+    )),
+  }
+}
+
+/// Returns the depth of the topmost mark, counted from the top.
+pub(crate) fn find_mark(vm: &Vm) -> Result<usize, String> {
+  vm.stack
+    .iter()
+    .rev()
+    .position(|value| matches!(value, Value::Mark))
+    .ok_or_else(|| "Unmatched mark".to_string())
+}
+
+pub(crate) fn pop(vm: &mut Vm) -> Result<(), String> {
+  vm.try_pop()?;
+  Ok(())
+}
+
+pub(crate) fn dup(vm: &mut Vm) -> Result<(), String> {
+  require(vm, 1)?;
+  let value = vm.stack[vm.stack.len() - 1].clone();
+  vm.stack.push(value);
+  Ok(())
+}
+
+pub(crate) fn exch(vm: &mut Vm) -> Result<(), String> {
+  require(vm, 2)?;
+  let len = vm.stack.len();
+  vm.stack.swap(len - 1, len - 2);
+  Ok(())
+}
+
+/// `a_n ... a_0 n index` -> `a_n ... a_0 a_n`
+pub(crate) fn index(vm: &mut Vm) -> Result<(), String> {
+  let index = pop_count(vm)?;
+  require(vm, index + 1)?;
+  let value = vm.stack[vm.stack.len() - index - 1].clone();
+  vm.stack.push(value);
+  Ok(())
+}
+
+/// `a_(n-1) ... a_0 n j roll` rotates the top `n` values by `j`
+/// positions upwards, e.g. `a b c 3 1 roll` -> `c a b`.
+pub(crate) fn roll(vm: &mut Vm) -> Result<(), String> {
+  let j = vm.try_pop()?;
+  let Value::Int(j) = j else {
+    return Err(format!(
+      "Expected an integer, got {}",
+      j.to_string()
+    ));
+  };
+  let n = pop_count(vm)?;
+  require(vm, n)?;
+  if n == 0 {
+    return Ok(());
+  }
+  let len = vm.stack.len();
+  let shift = j.rem_euclid(n as i32) as usize;
+  vm.stack[len - n..].rotate_right(shift);
+  Ok(())
+}
+
+/// `a_(n-1) ... a_0 n copy` duplicates the top `n` values.
+pub(crate) fn copy(vm: &mut Vm) -> Result<(), String> {
+  let n = pop_count(vm)?;
+  require(vm, n)?;
+  let len = vm.stack.len();
+  vm.stack.extend_from_within(len - n..);
+  Ok(())
+}
+
+pub(crate) fn clear(vm: &mut Vm) -> Result<(), String> {
+  vm.stack.clear();
+  Ok(())
+}
+
+pub(crate) fn count(vm: &mut Vm) -> Result<(), String> {
+  let len = vm.stack.len() as i32;
+  vm.stack.push(Value::Int(len));
+  Ok(())
+}
+
+pub(crate) fn mark(vm: &mut Vm) -> Result<(), String> {
+  vm.stack.push(Value::Mark);
+  Ok(())
+}
+
+/// Pops values down to and including the topmost mark.
+pub(crate) fn cleartomark(vm: &mut Vm) -> Result<(), String> {
+  let depth = find_mark(vm)?;
+  let len = vm.stack.len();
+  vm.stack.truncate(len - depth - 1);
+  Ok(())
+}
+
+/// Pushes the number of values above the topmost mark.
+pub(crate) fn counttomark(vm: &mut Vm) -> Result<(), String> {
+  let depth = find_mark(vm)?;
+  vm.stack.push(Value::Int(depth as i32));
+  Ok(())
+}
+
+/// `a b over` -> `a b a`
+pub(crate) fn over(vm: &mut Vm) -> Result<(), String> {
+  require(vm, 2)?;
+  let value = vm.stack[vm.stack.len() - 2].clone();
+  vm.stack.push(value);
+  Ok(())
+}
+
+/// `a b c rot` -> `b c a`
+pub(crate) fn rot(vm: &mut Vm) -> Result<(), String> {
+  require(vm, 3)?;
+  let len = vm.stack.len();
+  vm.stack[len - 3..].rotate_left(1);
+  Ok(())
+}
+
+/// `a b nip` -> `b`
+pub(crate) fn nip(vm: &mut Vm) -> Result<(), String> {
+  require(vm, 2)?;
+  let len = vm.stack.len();
+  vm.stack.remove(len - 2);
+  Ok(())
+}
+
+/// `a b tuck` -> `b a b`
+pub(crate) fn tuck(vm: &mut Vm) -> Result<(), String> {
+  require(vm, 2)?;
+  let len = vm.stack.len();
+  let value = vm.stack[len - 1].clone();
+  vm.stack.insert(len - 2, value);
+  Ok(())
+}
+
+/// `a b 2dup` -> `a b a b`
+pub(crate) fn dup2(vm: &mut Vm) -> Result<(), String> {
+  require(vm, 2)?;
+  let len = vm.stack.len();
+  vm.stack.extend_from_within(len - 2..);
+  Ok(())
+}
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test::run;
+
+  fn ints(values: &[i32]) -> Result<Vec<Value>, String> {
+    Ok(values.iter().map(|i| Value::Int(*i)).collect())
+  }
+
+  #[test]
+  fn test_stack_ops() {
+    assert_eq!(run("1 2 3 3 1 roll"), ints(&[3, 1, 2]));
+    assert_eq!(run("1 2 3 3 -1 roll"), ints(&[2, 3, 1]));
+    assert_eq!(run("1 2 3 2 copy"), ints(&[1, 2, 3, 2, 3]));
+    assert_eq!(run("1 2 clear 3"), ints(&[3]));
+    assert_eq!(run("1 2 count"), ints(&[1, 2, 2]));
+    assert_eq!(
+      run("1 mark 2 3 counttomark").map(|s| s[4].clone()),
+      Ok(Value::Int(2))
+    );
+    assert_eq!(run("1 mark 2 3 cleartomark"), ints(&[1]));
+    assert_eq!(run("1 2 over"), ints(&[1, 2, 1]));
+    assert_eq!(run("1 2 3 rot"), ints(&[2, 3, 1]));
+    assert_eq!(run("1 2 nip"), ints(&[2]));
+    assert_eq!(run("1 2 tuck"), ints(&[2, 1, 2]));
+    assert_eq!(run("1 2 2dup"), ints(&[1, 2, 1, 2]));
+    assert_eq!(run("1 2 3 2 index"), ints(&[1, 2, 3, 1]));
+    assert_eq!(run("1 2 3 2.0 index"), ints(&[1, 2, 3, 1]));
+    assert_eq!(
+      run("1 2 1.5 index"),
+      Err(
+        "<input>:1:9: Expected a non-negative integer, got 1.5"
+          .to_string()
+      )
+    );
+  }
+
+  #[test]
+  fn test_underflow() {
+    assert_eq!(
+      run("1 2 index"),
+      Err("<input>:1:5: Stack underflow".to_string())
+    );
+    assert_eq!(
+      run("1 over"),
+      Err("<input>:1:3: Stack underflow".to_string())
+    );
+    assert_eq!(
+      run("pop"),
+      Err("<input>:1:1: Stack underflow".to_string())
+    );
+    assert_eq!(
+      run("1 2 counttomark"),
+      Err("<input>:1:5: Unmatched mark".to_string())
+    );
+  }
+}
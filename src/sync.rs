@@ -0,0 +1,112 @@
+//! Pointer types and bounds which become thread-safe with the
+//! `sync` feature, so that `Vm` is `Send`. Without it,
+//! the cheaper `Rc` and `RefCell` are used.
+
+use std::{
+  any::Any,
+  io::{BufRead, Write},
+};
+
+use crate::{FileLoader, Vm};
+
+#[cfg(not(feature = "sync"))]
+mod imp {
+  use std::cell::{RefCell, RefMut};
+
+  /// `Rc`, or `Arc` with the `sync` feature
+  pub type Shared<T> = std::rc::Rc<T>;
+
+  /// `RefCell`, or `Mutex` with the `sync` feature
+  #[derive(Debug, Default)]
+  pub(crate) struct Lock<T>(RefCell<T>);
+
+  impl<T> Lock<T> {
+    pub(crate) fn new(value: T) -> Self {
+      Self(RefCell::new(value))
+    }
+
+    pub(crate) fn lock(&self) -> RefMut<'_, T> {
+      self.0.borrow_mut()
+    }
+  }
+
+  /// `Send` with the `sync` feature, nothing otherwise
+  pub trait MaybeSend {}
+  impl<T: ?Sized> MaybeSend for T {}
+
+  /// `Sync` with the `sync` feature, nothing otherwise
+  pub trait MaybeSync {}
+  impl<T: ?Sized> MaybeSync for T {}
+}
+
+#[cfg(feature = "sync")]
+mod imp {
+  use std::sync::{Mutex, MutexGuard};
+
+  /// `Rc`, or `Arc` with the `sync` feature
+  pub type Shared<T> = std::sync::Arc<T>;
+
+  /// `RefCell`, or `Mutex` with the `sync` feature
+  #[derive(Debug, Default)]
+  pub(crate) struct Lock<T>(Mutex<T>);
+
+  impl<T> Lock<T> {
+    pub(crate) fn new(value: T) -> Self {
+      Self(Mutex::new(value))
+    }
+
+    pub(crate) fn lock(&self) -> MutexGuard<'_, T> {
+      // A panic in a native function should not make the value
+      // unusable.
+      self.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+  }
+
+  /// `Send` with the `sync` feature, nothing otherwise
+  pub trait MaybeSend: Send {}
+  impl<T: ?Sized + Send> MaybeSend for T {}
+
+  /// `Sync` with the `sync` feature, nothing otherwise
+  pub trait MaybeSync: Sync {}
+  impl<T: ?Sized + Sync> MaybeSync for T {}
+}
+
+pub use imp::*;
+
+/// A function added by [`Vm::add_fn`]
+#[cfg(not(feature = "sync"))]
+pub type HostFn = dyn Fn(&mut Vm);
+/// A function added by [`Vm::add_fn`]
+#[cfg(feature = "sync")]
+pub type HostFn = dyn Fn(&mut Vm) + Send + Sync;
+
+#[cfg(not(feature = "sync"))]
+pub(crate) type NativeFn =
+  dyn Fn(&mut Vm) -> Result<(), String>;
+#[cfg(feature = "sync")]
+pub(crate) type NativeFn =
+  dyn Fn(&mut Vm) -> Result<(), String> + Send + Sync;
+
+#[cfg(not(feature = "sync"))]
+pub(crate) type AnyObject = dyn Any;
+#[cfg(feature = "sync")]
+pub(crate) type AnyObject = dyn Any + Send + Sync;
+
+#[cfg(not(feature = "sync"))]
+pub(crate) type DynFileLoader = dyn FileLoader;
+#[cfg(feature = "sync")]
+pub(crate) type DynFileLoader = dyn FileLoader + Send + Sync;
+
+/// The type of the input of a `Vm`
+#[cfg(not(feature = "sync"))]
+pub type DynBufRead = dyn BufRead;
+/// The type of the input of a `Vm`
+#[cfg(feature = "sync")]
+pub type DynBufRead = dyn BufRead + Send;
+
+/// The type of the outputs of a `Vm`
+#[cfg(not(feature = "sync"))]
+pub type DynWrite = dyn Write;
+/// The type of the outputs of a `Vm`
+#[cfg(feature = "sync")]
+pub type DynWrite = dyn Write + Send;
@@ -0,0 +1,191 @@
+//! Type introspection and conversion operators.
+
+use crate::{Value, Vm};
+
+/// Pushes the type name of a value as a symbol, e.g. `/integertype`.
+pub(crate) fn type_of(vm: &mut Vm) -> Result<(), String> {
+  let value = vm.try_pop()?;
+  vm.stack.push(Value::Sym(value.type_name().to_string()));
+  Ok(())
+}
+
+/// Converts a number or a string to an integer, truncating reals.
+pub(crate) fn cvi(vm: &mut Vm) -> Result<(), String> {
+  let value = vm.try_pop()?;
+  let num = match &value {
+    Value::Int(val) => *val as f64,
+    Value::Num(val) => *val as f64,
+    Value::Str(s) => s.trim().parse::<f64>().map_err(|_| {
+      format!("Cannot convert {s:?} to a number")
+    })?,
+    _ => {
+      return Err(format!(
+        "Cannot convert {} to an integer",
+        value.type_name()
+      ))
+    }
+  };
+  let int = num.trunc();
+  if !(i32::MIN as f64..=i32::MAX as f64).contains(&int) {
+    return Err(format!(
+      "{num} is out of the range of an integer"
+    ));
+  }
+  vm.stack.push(Value::Int(int as i32));
+  Ok(())
+}
+
+/// Converts a number or a string to a real.
+pub(crate) fn cvr(vm: &mut Vm) -> Result<(), String> {
+  let value = vm.try_pop()?;
+  let num = match &value {
+    Value::Str(s) => s.trim().parse::<f32>().map_err(|_| {
+      format!("Cannot convert {s:?} to a number")
+    })?,
+    _ => value.try_num()?,
+  };
+  vm.stack.push(Value::Num(num));
+  Ok(())
+}
+
+/// Converts any value to its string representation.
+pub(crate) fn cvs(vm: &mut Vm) -> Result<(), String> {
+  let value = vm.try_pop()?;
+  vm.stack.push(Value::Str(value.to_string()));
+  Ok(())
+}
+
+/// Converts a string to a literal name.
+pub(crate) fn cvn(vm: &mut Vm) -> Result<(), String> {
+  let value = vm.try_pop()?;
+  match value {
+    Value::Str(s) | Value::Sym(s) | Value::Op(s) => {
+      vm.stack.push(Value::Sym(s))
+    }
+    _ => {
+      return Err(format!(
+        "Cannot convert {} to a name",
+        value.type_name()
+      ))
+    }
+  }
+  Ok(())
+}
+
+/// Makes a literal name executable. Other values are left as is.
+pub(crate) fn cvx(vm: &mut Vm) -> Result<(), String> {
+  let value = match vm.try_pop()? {
+    Value::Sym(s) => Value::Op(s),
+    value => value,
+  };
+  vm.stack.push(value);
+  Ok(())
+}
+
+/// Makes an executable name literal. Other values are left as is.
+pub(crate) fn cvlit(vm: &mut Vm) -> Result<(), String> {
+  let value = match vm.try_pop()? {
+    Value::Op(s) => Value::Sym(s),
+    value => value,
+  };
+  vm.stack.push(value);
+  Ok(())
+}
+
+macro_rules! impl_predicate {
+  {$name:ident, $pat:pat} => {
+    pub(crate) fn $name(vm: &mut Vm) -> Result<(), String> {
+      let value = vm.try_pop()?;
+      vm.stack.push(Value::Int(matches!(value, $pat) as i32));
+      Ok(())
+    }
+  }
+}
+
+impl_predicate!(isint, Value::Int(_));
+impl_predicate!(isreal, Value::Num(_));
+impl_predicate!(isnum, Value::Int(_) | Value::Num(_));
+impl_predicate!(isstr, Value::Str(_));
+impl_predicate!(issym, Value::Sym(_));
+impl_predicate!(isblock, Value::Block(_));
+impl_predicate!(isnative, Value::Native(_));
+impl_predicate!(isarray, Value::Array(_));
+impl_predicate!(isdict, Value::Dict(_));
+
+#[cfg(test)]
+mod test {
+  use super::*;
+  use crate::test::run;
+
+  #[test]
+  fn test_type() {
+    let sym = |s: &str| Value::Sym(s.to_string());
+    assert_eq!(
+      run("1 type 1.5 type /a type (s) type { 1 } type"),
+      Ok(vec![
+        sym("integertype"),
+        sym("realtype"),
+        sym("nametype"),
+        sym("stringtype"),
+        sym("blocktype"),
+      ])
+    );
+    assert_eq!(
+      run("/+ load type"),
+      Ok(vec![sym("operatortype")])
+    );
+  }
+
+  #[test]
+  fn test_conversion() {
+    use Value::*;
+    assert_eq!(
+      run("2.7 cvi -2.7 cvi (42) cvi 3 cvr (1.5) cvr"),
+      Ok(vec![Int(2), Int(-2), Int(42), Num(3.), Num(1.5)])
+    );
+    assert_eq!(
+      run("12 cvs (abc) cvn /abc cvx cvlit"),
+      Ok(vec![
+        Str("12".to_string()),
+        Sym("abc".to_string()),
+        Sym("abc".to_string()),
+      ])
+    );
+    assert_eq!(run("/a cvx"), Ok(vec![Op("a".to_string())]));
+    assert_eq!(
+      run("(x) cvi"),
+      Err(
+        "<input>:1:5: Cannot convert \"x\" to a number"
+          .to_string()
+      )
+    );
+  }
+
+  #[test]
+  fn test_predicates() {
+    use Value::Int;
+    assert_eq!(
+      run("1 isnum 1.5 isnum /a isnum 1 isint 1.5 isint { } isblock"),
+      Ok(vec![Int(1), Int(1), Int(0), Int(1), Int(0), Int(1)])
+    );
+  }
+
+  #[test]
+  fn test_type_error() {
+    assert_eq!(
+      run("/a 1 +"),
+      Err(
+        "<input>:1:6: Binary arithmetic between incompatible \
+          types nametype and integertype"
+          .to_string()
+      )
+    );
+    assert_eq!(
+      run("{ } 1 2 for"),
+      Err(
+        "<input>:1:9: Expected a block, got integertype"
+          .to_string()
+      )
+    );
+  }
+}
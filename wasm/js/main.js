@@ -1,7 +1,20 @@
-import { init, entry, start_step } from "../pkg/index.js";
+import { init, entry, start_step, get_operators } from "../pkg/index.js";
 
 init();
 
+function showOperators() {
+    const groups = {};
+    for (const op of JSON.parse(get_operators())) {
+        const group = op.group ?? "other";
+        (groups[group] = groups[group] ?? []).push(op.name);
+    }
+    document.getElementById("operators").innerHTML = Object.entries(groups)
+        .map(([group, names]) => `<div><b>${group}</b>: <span class="code">${names.join(" ")}</span></div>`)
+        .join("");
+}
+
+showOperators();
+
 function runCommon(process, clearOutput=true, measureTime=true) {
     // Clear output
     const output = document.getElementById("output");
@@ -47,7 +60,9 @@ document.getElementById("startAutoStep").addEventListener("click", () => runComm
     updateButtonStates();
     runAutoStep();
 }, true, false));
-document.getElementById("step").addEventListener("click", () => runCommon(runStep, false));
+document.getElementById("step").addEventListener("click", () => runCommon(() => runStep(), false));
+document.getElementById("stepOver").addEventListener("click", () => runCommon(() => runStep((vm) => vm.step_over()), false));
+document.getElementById("stepOut").addEventListener("click", () => runCommon(() => runStep((vm) => vm.step_out()), false));
 document.getElementById("haltStep").addEventListener("click", () => runCommon((source) => {
     vm = null;
     updateButtonStates();
@@ -61,10 +76,10 @@ document.getElementById("clearCanvas").addEventListener("click", () => {
     ctx.clearRect(0, 0, canvas.width, canvas.height);
 });
 
-function runStep() {
+function runStep(step = (vm) => vm.step()) {
     if (vm) {
         try {
-            const ret = vm.step();
+            const ret = step(vm);
             const first = sourceText.substring(0, ret[0]);
             const middle = sourceText.substring(ret[0], ret[1]);
             const last = sourceText.substring(ret[1]);
@@ -172,6 +187,8 @@ function updateButtonStates() {
         document.getElementById("run").setAttribute("disabled", "");
         document.getElementById("startStep").setAttribute("disabled", "");
         document.getElementById("step").removeAttribute("disabled");
+        document.getElementById("stepOver").removeAttribute("disabled");
+        document.getElementById("stepOut").removeAttribute("disabled");
         document.getElementById("haltStep").removeAttribute("disabled");
     }
     else{
@@ -180,6 +197,8 @@ function updateButtonStates() {
         document.getElementById("run").removeAttribute("disabled");
         document.getElementById("startStep").removeAttribute("disabled");
         document.getElementById("step").setAttribute("disabled", "");
+        document.getElementById("stepOver").setAttribute("disabled", "");
+        document.getElementById("stepOut").setAttribute("disabled", "");
         document.getElementById("haltStep").setAttribute("disabled", "");
     }
 }
@@ -1,11 +1,47 @@
 mod utils;
 mod wasm_imports;
 
-use crate::wasm_imports::register_wasm_fn;
-use rustack::Vm;
+use std::{cell::RefCell, collections::HashMap};
+
+use crate::wasm_imports::{
+  register_wasm_fn, PageInput, PageOutput,
+};
+use rustack::{resolve_path, FileLoader, Pause, Vm};
 use serde::Serialize;
 use wasm_bindgen::prelude::*;
 
+thread_local! {
+  /// Script files that can be loaded by `include` and `run`
+  static FILES: RefCell<HashMap<String, String>> =
+    RefCell::new(HashMap::new());
+}
+
+struct ScriptFiles;
+
+impl FileLoader for ScriptFiles {
+  fn load(&self, path: &str) -> Result<String, String> {
+    FILES.with(|files| {
+      files
+        .borrow()
+        .get(path)
+        .cloned()
+        .ok_or_else(|| format!("File {path:?} is not found"))
+    })
+  }
+}
+
+fn new_vm() -> Vm {
+  let mut vm = Vm::builder()
+    .prelude(true)
+    .input(std::io::BufReader::new(PageInput::default()))
+    .output(PageOutput)
+    .error_output(PageOutput)
+    .file_loader(ScriptFiles)
+    .build();
+  register_wasm_fn(&mut vm);
+  vm
+}
+
 #[wasm_bindgen]
 extern "C" {
   #[wasm_bindgen(js_namespace = console)]
@@ -17,13 +53,44 @@ pub fn init() {
   utils::set_panic_hook();
 }
 
+/// Adds a script file that can be loaded by `include` and `run`.
+#[wasm_bindgen]
+pub fn add_file(path: &str, src: String) {
+  FILES.with(|files| {
+    files.borrow_mut().insert(resolve_path(None, path), src)
+  });
+}
+
+/// Returns the operators available to scripts in JSON, with their
+/// builtin groups.
+#[wasm_bindgen]
+pub fn get_operators() -> Result<String, JsValue> {
+  #[derive(Serialize)]
+  struct Operator {
+    name: String,
+    group: Option<&'static str>,
+  }
+
+  let ret: Vec<Operator> = new_vm()
+    .operators()
+    .into_iter()
+    .map(|op| Operator {
+      name: op.name,
+      group: op.group.map(|group| group.name()),
+    })
+    .collect();
+  serde_json::to_string(&ret)
+    .map_err(|e| JsValue::from_str(&e.to_string()))
+}
+
 #[wasm_bindgen]
 pub fn entry(src: &str) -> Result<String, JsValue> {
   let stack = {
-    let mut vm = Vm::new();
-    register_wasm_fn(&mut vm);
-    vm.parse_batch(std::io::Cursor::new(src));
-    vm.eval_all()?;
+    let mut vm = new_vm();
+    vm.parse_batch(std::io::Cursor::new(src))
+      .map_err(|e| JsValue::from_str(&e.render(false)))?;
+    vm.eval_all()
+      .map_err(|e| JsValue::from_str(&e.render(false)))?;
     format!("stack: {:?}\n", vm.get_stack())
   };
   Ok(stack)
@@ -31,12 +98,15 @@ pub fn entry(src: &str) -> Result<String, JsValue> {
 
 #[wasm_bindgen]
 pub struct VmHandle {
-  vm: Vm<'static>,
+  vm: Vm,
   tokens: Vec<String>,
+  /// The range of the source in the spans of the Vm, which also has
+  /// the prelude
+  source_range: (usize, usize),
 }
 
 #[wasm_bindgen]
-pub fn start_step(src: String) -> VmHandle {
+pub fn start_step(src: String) -> Result<VmHandle, JsValue> {
   let tokens = src
     .split([' ', '\t', '\r', '\n'])
     .filter_map(|tok| {
@@ -47,23 +117,82 @@ pub fn start_step(src: String) -> VmHandle {
       }
     })
     .collect();
-  let mut vm = Vm::new();
-  register_wasm_fn(&mut vm);
-  vm.parse_batch(std::io::Cursor::new(src));
-  VmHandle { vm, tokens }
+  let mut vm = new_vm();
+  vm.parse_batch(std::io::Cursor::new(src))
+    .map_err(|e| JsValue::from_str(&e.render(false)))?;
+  let source_range = vm
+    .source_map()
+    .files()
+    .last()
+    .map_or((0, 0), |file| (file.start, file.end()));
+  Ok(VmHandle {
+    vm,
+    tokens,
+    source_range,
+  })
+}
+
+impl VmHandle {
+  /// Converts a span to the range in the source, or an empty range
+  /// if the prelude is running, which is not highlighted.
+  fn highlight(&self, span: (usize, usize)) -> Vec<usize> {
+    let (start, end) = self.source_range;
+    if start <= span.0 && span.1 <= end {
+      vec![span.0 - start, span.1 - start]
+    } else {
+      vec![0, 0]
+    }
+  }
+
+  /// Returns the range of the token executed last.
+  fn pause_span(
+    &self,
+    pause: Pause,
+  ) -> Result<Vec<usize>, JsValue> {
+    if pause == Pause::Finished {
+      return Err(JsValue::from_str("Input tokens exhausted"));
+    }
+    let span =
+      self.vm.get_exec_stack().last().map_or((0, 0), |state| {
+        state.as_frame().current_span()
+      });
+    Ok(self.highlight(span))
+  }
 }
 
 #[wasm_bindgen]
 impl VmHandle {
   pub fn step(&mut self) -> Result<Vec<usize>, JsValue> {
     log(&format!("tokens: {:?}", self.tokens));
-    if let Some(span) = self.vm.eval_step()? {
-      Ok(vec![span.0, span.1])
+    if let Some(span) = self
+      .vm
+      .eval_step()
+      .map_err(|e| JsValue::from_str(&e.render(false)))?
+    {
+      Ok(self.highlight(span))
     } else {
       return Err(JsValue::from_str("Input tokens exhausted"));
     }
   }
 
+  /// Runs until the next token of the current frame.
+  pub fn step_over(&mut self) -> Result<Vec<usize>, JsValue> {
+    let pause = self
+      .vm
+      .step_over()
+      .map_err(|e| JsValue::from_str(&e.render(false)))?;
+    self.pause_span(pause)
+  }
+
+  /// Runs until the current function returns.
+  pub fn step_out(&mut self) -> Result<Vec<usize>, JsValue> {
+    let pause = self
+      .vm
+      .step_out()
+      .map_err(|e| JsValue::from_str(&e.render(false)))?;
+    self.pause_span(pause)
+  }
+
   pub fn get_stack(&self) -> Result<Vec<JsValue>, JsValue> {
     Ok(
       self
@@ -91,10 +220,15 @@ impl VmHandle {
       .iter()
       .map(|ex| {
         let frame = ex.as_frame();
+        // Top level definitions are kept in the Vm, not the frame
+        let vars = if frame.is_root() {
+          self.vm.root_vars()
+        } else {
+          &frame.vars
+        };
         ExecFrame {
           name: frame.name.clone(),
-          vars: frame
-            .vars
+          vars: vars
             .iter()
             .map(|(key, val)| [key.clone(), val.to_string()])
             .collect(),
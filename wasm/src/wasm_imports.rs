@@ -1,30 +1,38 @@
-use rustack::Vm;
+use rustack::{BuiltinGroup, Vm};
 use wasm_bindgen::prelude::*;
 
 pub(super) fn register_wasm_fn(vm: &mut Vm) {
-  vm.add_fn("puts".to_string(), Box::new(puts));
-  vm.add_fn("rectangle".to_string(), Box::new(rectangle));
-  vm.add_fn(
-    "set_fill_style".to_string(),
-    Box::new(set_fill_style),
+  use BuiltinGroup::{Canvas, Time};
+  vm.register_builtin(Time, "usertime", usertime);
+  vm.register_builtin(Canvas, "rectangle", wasm_rectangle);
+  vm.register_builtin(
+    Canvas,
+    "set_fill_style",
+    |r: f32, g: f32, b: f32| {
+      wasm_set_fill_style(&format!("rgb({r},{g},{b})"))
+    },
   );
-  vm.add_fn(
-    "set_stroke_style".to_string(),
-    Box::new(set_stroke_style),
+  vm.register_builtin(
+    Canvas,
+    "set_stroke_style",
+    |r: f32, g: f32, b: f32| {
+      wasm_set_stroke_style(&format!("rgb({r},{g},{b})"))
+    },
   );
-  vm.add_fn("begin_path".to_string(), Box::new(begin_path));
-  vm.add_fn("move_to".to_string(), Box::new(move_to));
-  vm.add_fn("line_to".to_string(), Box::new(line_to));
-  vm.add_fn("stroke".to_string(), Box::new(stroke));
-  vm.add_fn("rotate".to_string(), Box::new(rotate));
-  vm.add_fn("translate".to_string(), Box::new(translate));
-  vm.add_fn("save".to_string(), Box::new(save));
-  vm.add_fn("restore".to_string(), Box::new(restore));
+  vm.register_builtin(Canvas, "begin_path", wasm_begin_path);
+  vm.register_builtin(Canvas, "move_to", wasm_move_to);
+  vm.register_builtin(Canvas, "line_to", wasm_line_to);
+  vm.register_builtin(Canvas, "stroke", wasm_stroke);
+  vm.register_builtin(Canvas, "rotate", wasm_rotate);
+  vm.register_builtin(Canvas, "translate", wasm_translate);
+  vm.register_builtin(Canvas, "save", wasm_save);
+  vm.register_builtin(Canvas, "restore", wasm_restore);
 }
 
 #[wasm_bindgen(module = "/wasm_api.js")]
 extern "C" {
   pub(crate) fn wasm_print(s: &str);
+  pub(crate) fn wasm_read_line() -> Option<String>;
   pub(crate) fn wasm_rectangle(
     x0: f32,
     y0: f32,
@@ -43,70 +51,49 @@ extern "C" {
   pub(crate) fn wasm_restore();
 }
 
-fn puts(vm: &mut Vm) {
-  wasm_print(&format!(
-    "puts: {}\n",
-    vm.pop().unwrap().to_string()
-  ));
+/// Asks the user for the input a line at a time. The input ends
+/// when the prompt is cancelled.
+#[derive(Default)]
+pub(super) struct PageInput {
+  line: Vec<u8>,
+  pos: usize,
 }
 
-fn rectangle(vm: &mut Vm) {
-  let y1 = vm.pop().unwrap().as_num();
-  let x1 = vm.pop().unwrap().as_num();
-  let y0 = vm.pop().unwrap().as_num();
-  let x0 = vm.pop().unwrap().as_num();
-  wasm_rectangle(x0, y0, x1, y1);
+impl std::io::Read for PageInput {
+  fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+    if self.line.len() <= self.pos {
+      let Some(line) = wasm_read_line() else {
+        return Ok(0);
+      };
+      self.line = (line + "\n").into_bytes();
+      self.pos = 0;
+    }
+    let len = buf.len().min(self.line.len() - self.pos);
+    buf[..len]
+      .copy_from_slice(&self.line[self.pos..self.pos + len]);
+    self.pos += len;
+    Ok(len)
+  }
 }
 
-fn set_fill_style(vm: &mut Vm) {
-  let b = vm.pop().unwrap().as_num();
-  let g = vm.pop().unwrap().as_num();
-  let r = vm.pop().unwrap().as_num();
-  wasm_set_fill_style(&format!("rgb({r},{g},{b})"));
-}
-
-fn set_stroke_style(vm: &mut Vm) {
-  let b = vm.pop().unwrap().as_num();
-  let g = vm.pop().unwrap().as_num();
-  let r = vm.pop().unwrap().as_num();
-  wasm_set_stroke_style(&format!("rgb({r},{g},{b})"));
-}
-
-fn begin_path(_vm: &mut Vm) {
-  wasm_begin_path();
-}
-
-fn move_to(vm: &mut Vm) {
-  let y0 = vm.pop().unwrap().as_num();
-  let x0 = vm.pop().unwrap().as_num();
-  wasm_move_to(x0, y0);
-}
+/// Writes the output of the printing operators to the page.
+pub(super) struct PageOutput;
 
-fn line_to(vm: &mut Vm) {
-  let y0 = vm.pop().unwrap().as_num();
-  let x0 = vm.pop().unwrap().as_num();
-  wasm_line_to(x0, y0);
-}
-
-fn stroke(_vm: &mut Vm) {
-  wasm_stroke();
-}
-
-fn rotate(vm: &mut Vm) {
-  let angle = vm.pop().unwrap().as_num();
-  wasm_rotate(angle);
-}
-
-fn translate(vm: &mut Vm) {
-  let y = vm.pop().unwrap().as_num();
-  let x = vm.pop().unwrap().as_num();
-  wasm_translate(x, y);
-}
+impl std::io::Write for PageOutput {
+  fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+    wasm_print(&String::from_utf8_lossy(buf));
+    Ok(buf.len())
+  }
 
-fn save(_vm: &mut Vm) {
-  wasm_save();
+  fn flush(&mut self) -> std::io::Result<()> {
+    Ok(())
+  }
 }
 
-fn restore(_vm: &mut Vm) {
-  wasm_restore();
+/// `std::time::Instant` is not available in the browser.
+fn usertime() -> i32 {
+  thread_local! {
+    static ORIGIN: f64 = js_sys::Date::now();
+  }
+  ORIGIN.with(|origin| js_sys::Date::now() - origin) as i32
 }
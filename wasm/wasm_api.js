@@ -2,6 +2,10 @@ export function wasm_print(str){
     document.getElementById("output").value += str;
 }
 
+export function wasm_read_line(){
+    return window.prompt("Input for the script (cancel to end the input)");
+}
+
 export function wasm_set_fill_style(str){
     const canvas = document.getElementById("canvas");
     const ctx = canvas.getContext('2d');